@@ -1,16 +1,27 @@
+use std::cmp;
 use std::collections::{VecDeque, HashMap};
 use std::fmt;
 use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::Duration;
+use byteorder::{ByteOrder, BigEndian};
 use fibers::sync::mpsc;
+use fibers::time::timer::{self, Timeout};
 use futures::{self, Future, Poll, Async, Sink};
-use hpack_codec::Decoder as HpackDecoder;
+use trackable::error::ErrorKindExt;
+use hpack_codec::{Decoder as HpackDecoder, Encoder as HpackEncoder};
 
 use {Result, Error, ErrorKind};
-use frame::{self, Frame, SettingsFrame, FrameSink, FrameStream};
-use header::Header;
-use preface::{self, ReadPreface};
-use setting::{Setting, Settings};
-use stream::{StreamId, Stream, StreamHandle, StreamItem};
+use extension::ExtensionHandler;
+use flow_control::{Window, ReplenishPolicy, ReplenishOnHalfWindow};
+use frame::{self, Frame, ExtensionFrame, SettingsFrame, FrameSink, FrameStream};
+use header::{Header, HeaderKind};
+use priority::Priority;
+use priority_tree::PriorityTree;
+use push::{PushPolicy, AcceptAllPushes};
+use preface::{self, ReadPreface, WritePreface};
+use setting::{Setting, Settings, MAX_FLOW_CONTROL_WINDOW_SIZE};
+use stream::{StreamId, Stream, StreamHandle, StreamCommand, StreamState};
 
 // TODO: move
 pub struct Bytes(Box<AsRef<[u8]> + Send + 'static>);
@@ -33,101 +44,1143 @@ impl fmt::Debug for Bytes {
     }
 }
 
+const DEFAULT_SETTINGS_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_MAX_BUFFERED_FRAMES: usize = 1024;
+const DEFAULT_MAX_SEND_QUEUE_SIZE: u32 = 1024 * 1024;
+const MAX_HEADER_BLOCK_SIZE: usize = 1024 * 1024;
+
+// HPACK tables are sized in `u16` by `hpack_codec`.
+const MAX_HEADER_TABLE_SIZE: u32 = 0xFFFF;
+
 // TODO: move
 #[derive(Debug)]
 pub enum Event {
     Stream(Stream),
     Pong { data: [u8; 8] },
+    GoAway {
+        last_stream_id: StreamId,
+        error: Error,
+        debug_data: Vec<u8>,
+    },
+    Pushed {
+        parent: StreamId,
+        promised_request: Header,
+        stream: Stream,
+    },
 }
 
 #[derive(Debug)]
 pub struct Connection<R, W: Write> {
+    is_server: bool,
     is_settings_received: bool,
     events: VecDeque<Event>,
     stream: FrameStream<R>,
     sink: FrameSink<W, Bytes>,
     settings: Settings,
+    pending_settings: VecDeque<Vec<Setting>>,
+    settings_timeout: Option<Duration>,
+    settings_timer: Option<Timeout>,
+    peer_settings: Settings,
+    send_window: Window,
+    recv_window: Window,
+
+    // The size up to which the connection-level receive window is replenished.
+    recv_window_size: u32,
+
+    // Octets of DATA delivered to `Stream`s but not read by the application yet.
+    recv_unread: u32,
+    replenish_policy: Arc<ReplenishPolicy>,
+    push_policy: Arc<PushPolicy>,
+    extension_handlers: HashMap<u8, Box<ExtensionHandler>>,
+    unknown_frames: u64,
+    max_buffered_frames: usize,
+    max_send_queue_size: u32,
     next_self_stream_id: StreamId,
     next_peer_stream_id: StreamId,
     streams: HashMap<StreamId, StreamHandle>,
-    stream_item_tx: mpsc::Sender<(StreamId, StreamItem)>,
-    stream_item_rx: mpsc::Receiver<(StreamId, StreamItem)>,
+    stream_command_tx: mpsc::Sender<(StreamId, StreamCommand)>,
+    stream_command_rx: mpsc::Receiver<(StreamId, StreamCommand)>,
     hpack_decoder: HpackDecoder,
+    hpack_encoder: HpackEncoder,
+    max_encoder_table_size: u16,
+
+    // The size of the HPACK encoder table which should be signaled to the peer.
+    hpack_table_size_update: Option<u16>,
+    continuing_header_block: Option<HeaderBlock>,
+    last_peer_stream_id: StreamId,
+    shutdown_phase: Option<ShutdownPhase>,
+
+    // Payloads of the PING frames sent by `ping` method which have not been acknowledged yet.
+    outstanding_pings: Vec<[u8; 8]>,
+    goaway_last_stream_id: Option<StreamId>,
+
+    // Locally initiated streams waiting for room under the peer's `SETTINGS_MAX_CONCURRENT_STREAMS`.
+    waiting_streams: VecDeque<StreamId>,
+    priority_tree: PriorityTree,
+    connection_error: Option<Error>,
 }
 impl<R: Read, W: Write> Connection<R, W> {
+    /// Accepts a connection from a client with the default configuration.
+    ///
+    /// Use `ConnectionBuilder` to change the configuration.
     pub fn accept(reader: R, writer: W) -> Accept<R, W> {
-        let future = preface::read_preface(reader);
-        Accept {
-            future,
-            writer: Some(writer),
+        ConnectionBuilder::new().accept(reader, writer)
+    }
+
+    /// Connects to a server with the default configuration.
+    ///
+    /// Use `ConnectionBuilder` to change the configuration.
+    pub fn connect(reader: R, writer: W) -> Connect<R, W> {
+        ConnectionBuilder::new().connect(reader, writer)
+    }
+
+    /// Opens a new stream initiated by this endpoint.
+    ///
+    /// Clients use odd-numbered stream identifiers and servers use even-numbered ones.
+    ///
+    /// If the number of the active streams initiated by this endpoint has reached
+    /// the peer's `SETTINGS_MAX_CONCURRENT_STREAMS`, the new stream waits
+    /// (in the order of the stream identifiers) until the limit leaves room.
+    /// A HEADERS frame is not sent on the stream while waiting.
+    pub fn open_stream(&mut self) -> Result<Stream> {
+        track_assert!(self.shutdown_phase.is_none(), ErrorKind::RefusedStream);
+        track_assert!(
+            self.goaway_last_stream_id.is_none(),
+            ErrorKind::RefusedStream
+        );
+        let stream_id = self.next_self_stream_id;
+        self.next_self_stream_id = track!(StreamId::new(stream_id.as_u32() + 2))?;
+
+        let stream = self.new_stream(stream_id);
+        self.waiting_streams.push_back(stream_id);
+        self.admit_waiting_streams();
+        Ok(stream)
+    }
+
+    /// Promises the peer to push the response for `request_header` on the stream `stream_id`.
+    ///
+    /// A PUSH_PROMISE frame is sent and the new stream in "reserved (local)" state is returned.
+    /// The response should be sent on the resulting stream.
+    ///
+    /// Only servers can push streams, and the streams are counted toward the peer's
+    /// `SETTINGS_MAX_CONCURRENT_STREAMS` from the time they are promised.
+    pub fn push_promise(&mut self, stream_id: StreamId, request_header: Header) -> Result<Stream> {
+        track_assert!(self.is_server, ErrorKind::ProtocolError);
+        track_assert!(self.shutdown_phase.is_none(), ErrorKind::RefusedStream);
+        track_assert!(
+            self.goaway_last_stream_id.is_none(),
+            ErrorKind::RefusedStream
+        );
+
+        // > PUSH_PROMISE MUST NOT be sent if the SETTINGS_ENABLE_PUSH setting of the
+        // > peer endpoint is set to 0.
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.6)
+        track_assert!(
+            self.peer_settings.enable_push,
+            ErrorKind::RefusedStream,
+            "The peer has disabled server push"
+        );
+        if let Some(max) = self.peer_settings.max_concurrent_streams {
+            let count = self.count_self_initiated_streams();
+            track_assert!(
+                count < max as usize,
+                ErrorKind::RefusedStream,
+                "Too many concurrent streams: max={}",
+                max
+            );
+        }
+
+        // > PUSH_PROMISE frames MUST only be sent on a peer-initiated stream that
+        // > is in either the "open" or "half-closed (remote)" state.
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.6)
+        track_assert!(
+            self.is_peer_initiated_stream(stream_id),
+            ErrorKind::ProtocolError,
+            "stream_id={:?}",
+            stream_id
+        );
+        if let Some(stream) = self.streams.get(&stream_id) {
+            let state = stream.state();
+            track_assert!(
+                state == StreamState::Open || state == StreamState::HalfClosedRemote,
+                ErrorKind::StreamClosed,
+                "stream_id={:?}, state={:?}",
+                stream_id,
+                stream.state()
+            );
+        } else {
+            track_panic!(ErrorKind::StreamClosed, "stream_id={:?}", stream_id);
+        }
+
+        let block = track!(self.encode_header(&request_header))?;
+        let promise_stream_id = self.next_self_stream_id;
+        self.next_self_stream_id = track!(StreamId::new(promise_stream_id.as_u32() + 2))?;
+
+        // The four octets of the promised stream identifier are included in the payload.
+        self.write_header_block(stream_id, block, 4, |fragment, end_headers| {
+            frame::PushPromiseFrame {
+                stream_id,
+                promise_stream_id,
+                end_headers,
+                padding_len: None,
+                fragment,
+            }.into()
+        });
+        let stream = self.new_stream(promise_stream_id);
+        track!(
+            self.streams
+                .get_mut(&promise_stream_id)
+                .expect("Never fails")
+                .reserve_local()
+        )?;
+        Ok(stream)
+    }
+
+    /// Returns the number of the frames of unknown types which have been received.
+    ///
+    /// Such frames are discarded as RFC 7540 requires,
+    /// unless a handler is registered for their types by `register_extension_handler`.
+    pub fn unknown_frames(&self) -> u64 {
+        self.unknown_frames
+    }
+
+    /// Registers `handler` as the handler of the extension frames of the type `frame_type`.
+    ///
+    /// If a handler is already registered for the type, it is replaced.
+    /// `frame_type` must not be one of the frame types defined in RFC 7540.
+    pub fn register_extension_handler<H>(&mut self, frame_type: u8, handler: H) -> Result<()>
+    where
+        H: ExtensionHandler + 'static,
+    {
+        track_assert!(
+            frame::is_extension_frame_type(frame_type),
+            ErrorKind::InternalError,
+            "frame_type={}",
+            frame_type
+        );
+        self.extension_handlers.insert(frame_type, Box::new(handler));
+        Ok(())
+    }
+
+    /// Sends the extension frame `frame`.
+    ///
+    /// The type of `frame` must not be one of the frame types defined in RFC 7540,
+    /// and the payload must not exceed the peer's `SETTINGS_MAX_FRAME_SIZE`.
+    pub fn send_extension_frame(&mut self, frame: ExtensionFrame<Bytes>) -> Result<()> {
+        track_assert!(
+            frame::is_extension_frame_type(frame.frame_type),
+            ErrorKind::InternalError,
+            "frame_type={}",
+            frame.frame_type
+        );
+        track_assert!(
+            frame.payload_len() <= self.peer_settings.max_frame_size as usize,
+            ErrorKind::FrameSizeError,
+            "payload_len={}",
+            frame.payload_len()
+        );
+        self.sink.start_write_frame(frame);
+        Ok(())
+    }
+
+    /// Sends `header` on the stream `stream_id` as a HEADERS frame.
+    ///
+    /// If the header block does not fit in a frame, it is split into CONTINUATION frames.
+    /// If the stream is waiting for room under the peer's `SETTINGS_MAX_CONCURRENT_STREAMS`,
+    /// the header is held back until the stream is admitted.
+    pub fn send_headers(&mut self, stream_id: StreamId, header: Header, end_stream: bool) -> Result<()> {
+        if self.waiting_streams.contains(&stream_id) {
+            let stream = self.streams.get_mut(&stream_id).expect("Never fails");
+            track!(stream.hold_header(header, end_stream))?;
+            return Ok(());
+        }
+        track_assert!(
+            self.streams.contains_key(&stream_id),
+            ErrorKind::StreamClosed,
+            "stream_id={:?}",
+            stream_id
+        );
+        track!(self.write_headers(stream_id, &header, end_stream))?;
+        self.flush_data();
+        Ok(())
+    }
+
+    /// Sends `data` on the stream `stream_id`.
+    ///
+    /// The data is split into DATA frames and is held back
+    /// until the flow-control windows of both the stream and the connection allow it to be sent.
+    pub fn send_data(&mut self, stream_id: StreamId, data: Bytes, end_stream: bool) -> Result<()> {
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            track!(stream.enqueue_data(data, end_stream))?;
+        } else {
+            track_panic!(ErrorKind::StreamClosed, "stream_id={:?}", stream_id);
+        }
+        self.flush_data();
+        Ok(())
+    }
+
+    /// Sends `trailers` on the stream `stream_id` as a HEADERS frame with the END_STREAM flag.
+    ///
+    /// The trailers are held back until all the data passed to `send_data` has been sent.
+    /// They must not contain pseudo-header fields.
+    pub fn send_trailers(&mut self, stream_id: StreamId, trailers: Header) -> Result<()> {
+        track!(trailers.validate(HeaderKind::Trailers))?;
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            track!(stream.enqueue_trailers(trailers))?;
+        } else {
+            track_panic!(ErrorKind::StreamClosed, "stream_id={:?}", stream_id);
+        }
+        self.flush_data();
+        Ok(())
+    }
+
+    /// Sets the policy which decides when to send WINDOW_UPDATE frames.
+    ///
+    /// The default policy is `ReplenishOnHalfWindow`.
+    pub fn set_replenish_policy<P>(&mut self, policy: P)
+    where
+        P: ReplenishPolicy + 'static,
+    {
+        self.replenish_policy = Arc::new(policy);
+    }
+
+    /// Sets the policy which decides whether streams pushed by the server are accepted.
+    ///
+    /// The default policy is `AcceptAllPushes`.
+    pub fn set_push_policy<P>(&mut self, policy: P)
+    where
+        P: PushPolicy + 'static,
+    {
+        self.push_policy = Arc::new(policy);
+    }
+
+    /// Sets the deadline within which the peer must acknowledge our SETTINGS frames.
+    ///
+    /// If the peer does not acknowledge them in time,
+    /// the connection fails with a GOAWAY frame carrying `SETTINGS_TIMEOUT`.
+    /// `None` disables the deadline.
+    ///
+    /// The default value is 10 seconds.
+    pub fn set_settings_timeout(&mut self, timeout: Option<Duration>) {
+        self.settings_timeout = timeout;
+        self.settings_timer = if self.pending_settings.is_empty() {
+            None
+        } else {
+            timeout.map(timer::timeout)
+        };
+    }
+
+    /// Resets the stream `stream_id` by sending a RST_STREAM frame.
+    ///
+    /// If the stream has already been closed, this method does nothing.
+    /// A stream in "idle" state is closed without sending any frame.
+    pub fn reset_stream(&mut self, stream_id: StreamId, error: Error) {
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            if stream.state() == StreamState::Idle {
+                // > RST_STREAM frames MUST NOT be sent for a stream in the "idle" state.
+                // >
+                // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.4)
+                //
+                // The peer does not know the stream yet, so it is just dropped.
+                stream.discard(error);
+            } else if !stream.is_closed() {
+                stream.reset().expect("Never fails");
+                self.sink.start_write_frame(frame::RstStreamFrame { stream_id, error });
+            }
+        }
+    }
+
+    /// Starts shutting down this connection gracefully.
+    ///
+    /// First a GOAWAY frame with the maximum stream identifier is sent,
+    /// and after a PING round trip a final GOAWAY frame carrying the identifier of
+    /// the last processed stream follows.
+    /// After that the connection ends once all in-flight streams are finished.
+    pub fn shutdown(&mut self) {
+        if self.shutdown_phase.is_some() {
+            return;
+        }
+
+        // > A server that is attempting to gracefully shut down a
+        // > connection SHOULD send an initial GOAWAY frame with the last stream
+        // > identifier set to 2^31-1 and a NO_ERROR code.  This signals to the
+        // > client that a shutdown is imminent and that initiating further
+        // > requests is prohibited.  After allowing time for any in-flight
+        // > stream creation (at least one round-trip time), the server can send
+        // > another GOAWAY frame with an updated last stream identifier.
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.8)
+        self.sink.start_write_frame(frame::GoawayFrame {
+            last_stream_id: StreamId::new_unchecked(0x7FFF_FFFF),
+            error: ErrorKind::NoError.into(),
+            debug_data: Vec::new(),
+        });
+
+        // The payload must be distinguishable from the ones of the application's PINGs.
+        let mut data = [0; 8];
+        let mut n = 0;
+        loop {
+            BigEndian::write_u64(&mut data, n);
+            if !self.outstanding_pings.contains(&data) {
+                break;
+            }
+            n += 1;
         }
+        self.sink.start_write_frame(frame::PingFrame { ack: false, data });
+        self.shutdown_phase = Some(ShutdownPhase::WaitingPong(data));
     }
 
     pub fn ping(&mut self, data: [u8; 8]) {
+        self.outstanding_pings.push(data);
         self.sink.start_write_frame(
             frame::PingFrame { ack: false, data },
         );
     }
 
-    fn new(reader: R, writer: W) -> Self {
-        let settings = Settings::default();
+    fn new(reader: R, writer: W, is_server: bool, builder: &ConnectionBuilder) -> Self {
+        let local_settings = builder.settings.to_vec();
+        let mut pending_settings = VecDeque::new();
+        pending_settings.push_back(local_settings.clone());
+
         let mut sink = FrameSink::new(writer);
-        sink.start_write_frame(SettingsFrame::Syn(vec![])); // TODO:
+        sink.start_write_frame(SettingsFrame::Syn(local_settings));
+
+        // > The connection flow-control window can only be changed using WINDOW_UPDATE frames.
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.9.2)
+        let mut recv_window = Window::new(Settings::default().initial_window_size);
+        let initial_increment = builder
+            .connection_window_size
+            .saturating_sub(Settings::default().initial_window_size);
+        if initial_increment > 0 {
+            recv_window.increase(initial_increment).expect("Never fails");
+            sink.start_write_frame(frame::WindowUpdateFrame {
+                stream_id: StreamId::connection_control_stream_id(),
+                window_size_increment: initial_increment,
+            });
+        }
+
+        let (next_self_stream_id, next_peer_stream_id) = if is_server {
+            (StreamId::from(2u8), StreamId::from(1u8))
+        } else {
+            (StreamId::from(1u8), StreamId::from(2u8))
+        };
+
+        let default_table_size = Settings::default().header_table_size as u16;
+        let encoder_table_size = cmp::min(builder.encoder_table_size, default_table_size);
 
-        let (stream_item_tx, stream_item_rx) = mpsc::channel();
+        let (stream_command_tx, stream_command_rx) = mpsc::channel();
         Connection {
+            is_server,
             is_settings_received: false,
             events: VecDeque::new(),
             stream: FrameStream::new(reader),
             sink,
-            settings,
-            next_self_stream_id: StreamId::from(2u8), // TODO: use `is_server`
-            next_peer_stream_id: StreamId::from(1u8),
+            settings: Settings::default(),
+            pending_settings,
+            settings_timeout: builder.settings_timeout,
+            settings_timer: builder.settings_timeout.map(timer::timeout),
+            peer_settings: Settings::default(),
+            send_window: Window::new(Settings::default().initial_window_size),
+            recv_window,
+            recv_window_size: builder.connection_window_size,
+            recv_unread: 0,
+            replenish_policy: builder.replenish_policy.clone(),
+            push_policy: builder.push_policy.clone(),
+            extension_handlers: HashMap::new(),
+            unknown_frames: 0,
+            max_buffered_frames: builder.max_buffered_frames,
+            max_send_queue_size: builder.max_send_queue_size,
+            next_self_stream_id,
+            next_peer_stream_id,
             streams: HashMap::new(),
-            stream_item_tx,
-            stream_item_rx,
-            hpack_decoder: HpackDecoder::new(4096),
+            stream_command_tx,
+            stream_command_rx,
+            // Until our SETTINGS frame is acknowledged, the peer may use the default table size.
+            hpack_decoder: HpackDecoder::new(default_table_size),
+            hpack_encoder: HpackEncoder::new(encoder_table_size),
+            max_encoder_table_size: builder.encoder_table_size,
+            hpack_table_size_update: if encoder_table_size != default_table_size {
+                Some(encoder_table_size)
+            } else {
+                None
+            },
+            continuing_header_block: None,
+            last_peer_stream_id: StreamId::connection_control_stream_id(),
+            shutdown_phase: None,
+            outstanding_pings: Vec::new(),
+            goaway_last_stream_id: None,
+            waiting_streams: VecDeque::new(),
+            priority_tree: PriorityTree::new(),
+            connection_error: None,
+        }
+    }
+    fn new_stream(&mut self, stream_id: StreamId) -> Stream {
+        let (stream, handle) = Stream::new(
+            stream_id,
+            self.stream_command_tx.clone(),
+            self.peer_settings.initial_window_size,
+            self.settings.initial_window_size,
+            self.max_send_queue_size,
+        );
+        self.streams.insert(stream_id, handle);
+        self.priority_tree.open(stream_id);
+        stream
+    }
+    fn count_self_initiated_streams(&self) -> usize {
+        self.streams
+            .iter()
+            .filter(|&(&id, stream)| {
+                !self.is_peer_initiated_stream(id) && !stream.is_closed() &&
+                    !self.waiting_streams.contains(&id)
+            })
+            .count()
+    }
+
+    /// Counts the peer-initiated streams which count toward our `SETTINGS_MAX_CONCURRENT_STREAMS`.
+    ///
+    /// > Streams that are in the "open" state or in either of the "half-closed" states
+    /// > count toward the maximum number of streams that an endpoint is permitted to open.
+    /// > Streams in any of these three states count toward the limit advertised in the
+    /// > SETTINGS_MAX_CONCURRENT_STREAMS setting.  Streams in either of the
+    /// > "reserved" states do not count toward the stream limit.
+    /// >
+    /// > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-5.1.2)
+    fn count_peer_initiated_streams(&self) -> usize {
+        self.streams
+            .iter()
+            .filter(|&(&id, stream)| {
+                let state = stream.state();
+                self.is_peer_initiated_stream(id) &&
+                    (state == StreamState::Open || state == StreamState::HalfClosedLocal ||
+                         state == StreamState::HalfClosedRemote)
+            })
+            .count()
+    }
+
+    /// Lets the waiting streams go as long as the peer's `SETTINGS_MAX_CONCURRENT_STREAMS` allows.
+    fn admit_waiting_streams(&mut self) {
+        while let Some(&stream_id) = self.waiting_streams.front() {
+            let is_reset = if let Some(stream) = self.streams.get(&stream_id) {
+                stream.is_closed()
+            } else {
+                true
+            };
+            if is_reset {
+                // The stream has been reset before it was admitted.
+                self.waiting_streams.pop_front();
+                continue;
+            }
+
+            let count = self.count_self_initiated_streams();
+            if let Some(max) = self.peer_settings.max_concurrent_streams {
+                if count >= max as usize {
+                    break;
+                }
+            }
+            self.waiting_streams.pop_front();
+
+            let held = self.streams
+                .get_mut(&stream_id)
+                .expect("Never fails")
+                .take_held_header();
+            if let Some((header, end_stream)) = held {
+                if let Err(e) = track!(self.write_headers(stream_id, &header, end_stream)) {
+                    self.stream_error(stream_id, e);
+                }
+            }
+        }
+    }
+
+    /// Decodes the header block `block` of the stream `stream_id`.
+    ///
+    /// If the header list exceeds our `SETTINGS_MAX_HEADER_LIST_SIZE`,
+    /// the stream is reset and `None` is returned.
+    fn decode_header(&mut self, stream_id: StreamId, block: &[u8]) -> Result<Option<Header>> {
+        let limit = self.settings.max_header_list_size;
+        let header = track!(Header::decode_with_limit(
+            &mut self.hpack_decoder,
+            block,
+            limit
+        ))?;
+        if header.is_none() {
+            let e = ErrorKind::ProtocolError.cause(format!(
+                "Too large header list: limit={:?}",
+                limit
+            ));
+            self.stream_error(stream_id, track!(Error::from(e)));
+        }
+        Ok(header)
+    }
+
+    /// Encodes `header` using the HPACK encoder of this connection.
+    ///
+    /// If the list size of `header` exceeds the peer's `SETTINGS_MAX_HEADER_LIST_SIZE`,
+    /// an `InternalError` will be returned.
+    fn encode_header(&mut self, header: &Header) -> Result<Vec<u8>> {
+        if let Some(limit) = self.peer_settings.max_header_list_size {
+            track_assert!(
+                header.list_size() <= limit as usize,
+                ErrorKind::InternalError,
+                "Too large header list: size={}, limit={}",
+                header.list_size(),
+                limit
+            );
+        }
+        let mut block = Vec::new();
+        if let Some(size) = self.hpack_table_size_update.take() {
+            // > This dynamic table size update MUST occur at the beginning of the first
+            // > header block following the change to the dynamic table size.
+            // >
+            // > [RFC 7541](https://tools.ietf.org/html/rfc7541#section-4.2)
+            //
+            // The encoder has been replaced with a new one having the empty table,
+            // so the table of the peer is cleared first.
+            encode_dynamic_table_size_update(0, &mut block);
+            encode_dynamic_table_size_update(size, &mut block);
+        }
+        block.extend(track!(header.encode(&mut self.hpack_encoder))?);
+        Ok(block)
+    }
+
+    /// Writes `header` as a HEADERS frame (and CONTINUATION frames) of the stream `stream_id`.
+    fn write_headers(&mut self, stream_id: StreamId, header: &Header, end_stream: bool) -> Result<()> {
+        // Checks the state transition before updating the HPACK context.
+        let state = self.streams[&stream_id].state();
+        track!(state.send_headers(end_stream))?;
+
+        let block = track!(self.encode_header(header))?;
+        self.streams
+            .get_mut(&stream_id)
+            .expect("Never fails")
+            .send_headers(end_stream)
+            .expect("Never fails");
+        self.write_header_block(stream_id, block, 0, |fragment, end_headers| {
+            frame::HeadersFrame {
+                stream_id,
+                end_stream,
+                end_headers,
+                priority: None,
+                padding_len: None,
+                fragment,
+            }.into()
+        });
+        Ok(())
+    }
+
+    /// Writes the header block `block` as a HEADERS or PUSH_PROMISE frame
+    /// (made by `make_first_frame`) followed by zero or more CONTINUATION frames.
+    ///
+    /// `overhead` is the size of the fields other than the fragment in the payload of the first frame.
+    fn write_header_block<F>(
+        &mut self,
+        stream_id: StreamId,
+        mut block: Vec<u8>,
+        overhead: usize,
+        make_first_frame: F,
+    ) where
+        F: FnOnce(Bytes, bool) -> Frame<Bytes>,
+    {
+        // > Header blocks MUST be transmitted as a contiguous sequence of frames, with
+        // > no interleaved frames of any other type or from any other stream.
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-4.3)
+        //
+        // NOTE: `FrameSink` never interleaves DATA frames into a sequence of control frames.
+        let max_frame_size = self.peer_settings.max_frame_size as usize;
+        let first_len = cmp::min(block.len(), max_frame_size - overhead);
+        let rest = block.split_off(first_len);
+        self.sink
+            .start_write_frame(make_first_frame(Bytes::new(block), rest.is_empty()));
+
+        let mut offset = 0;
+        while offset < rest.len() {
+            let end = cmp::min(offset + max_frame_size, rest.len());
+            self.sink.start_write_frame(frame::ContinuationFrame {
+                stream_id,
+                end_headers: end == rest.len(),
+                payload: Bytes::new(rest[offset..end].to_vec()),
+            });
+            offset = end;
+        }
+    }
+    fn prioritize(&mut self, stream_id: StreamId, priority: Priority) -> bool {
+        if let Err(e) = track!(self.priority_tree.set_priority(stream_id, priority)) {
+            self.stream_error(stream_id, e);
+            false
+        } else {
+            true
+        }
+    }
+    /// Schedules the next DATA frame to be written.
+    ///
+    /// Streams are selected in accordance with the priority tree.
+    /// To keep the interleaving responsive to new frames,
+    /// at most one DATA frame is handed to the sink at a time.
+    fn flush_data(&mut self) {
+        let max_frame_size = self.peer_settings.max_frame_size;
+        while !self.sink.has_pending_data_frames() {
+            let max_len = cmp::min(max_frame_size, self.send_window.available());
+            if !self.streams.values().any(|s| s.has_sendable_data(max_len)) {
+                // The priority tree is not traversed if no stream has anything to send.
+                break;
+            }
+            let next = {
+                let streams = &self.streams;
+                self.priority_tree.next_stream(|id| {
+                    if let Some(stream) = streams.get(&id) {
+                        stream.has_sendable_data(max_len)
+                    } else {
+                        false
+                    }
+                })
+            };
+            let stream_id = if let Some(stream_id) = next {
+                stream_id
+            } else {
+                break;
+            };
+
+            let trailers = self.streams
+                .get_mut(&stream_id)
+                .and_then(|s| s.pop_trailers());
+            if let Some(trailers) = trailers {
+                // All the preceding DATA frames of the stream have already been written,
+                // so the trailers can be sent as a control frame.
+                if let Err(e) = track!(self.write_headers(stream_id, &trailers, true)) {
+                    self.stream_error(stream_id, e);
+                }
+                continue;
+            }
+
+            let frame = self.streams
+                .get_mut(&stream_id)
+                .and_then(|s| s.pop_data_frame(max_len))
+                .expect("Never fails");
+            let len = frame.data.as_ref().len() as u32;
+            self.send_window.consume(len).expect("Never fails");
+            self.priority_tree.consume(stream_id, len);
+            self.sink.start_write_frame(frame);
+        }
+    }
+    /// Sends WINDOW_UPDATE frames for the received DATA which is not waiting to be read.
+    ///
+    /// Octets delivered to `Stream`s are kept out of the windows until the application reads them,
+    /// so the peer can not send more data than the application consumes.
+    fn replenish_windows(&mut self, stream_id: StreamId) {
+        // The connection-level window is not affected by SETTINGS_INITIAL_WINDOW_SIZE.
+        let window_size = self.recv_window_size;
+        let consumed = i64::from(window_size) - self.recv_window.size() - i64::from(self.recv_unread);
+        if consumed > 0 && self.replenish_policy.should_replenish(consumed as u32, window_size) {
+            self.recv_window.increase(consumed as u32).expect("Never fails");
+            self.sink.start_write_frame(frame::WindowUpdateFrame {
+                stream_id: StreamId::connection_control_stream_id(),
+                window_size_increment: consumed as u32,
+            });
+        }
+
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            if stream.is_remote_closed() {
+                // No more DATA frames will arrive on this stream.
+                return;
+            }
+            let window_size = self.settings.initial_window_size;
+            let consumed = i64::from(window_size) - stream.recv_window().size() -
+                i64::from(stream.recv_unread());
+            if consumed > 0 && self.replenish_policy.should_replenish(consumed as u32, window_size) {
+                stream.recv_window_mut().increase(consumed as u32).expect("Never fails");
+                self.sink.start_write_frame(frame::WindowUpdateFrame {
+                    stream_id,
+                    window_size_increment: consumed as u32,
+                });
+            }
+        }
+    }
+    /// Handles the stream error `error` which occurred on the stream `stream_id`.
+    ///
+    /// The stream is closed by sending a RST_STREAM frame and the connection is kept alive.
+    /// A stream in "idle" state is closed without sending any frame.
+    fn stream_error(&mut self, stream_id: StreamId, error: Error) {
+        // > RST_STREAM frames MUST NOT be sent for a stream in the "idle" state.
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.4)
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            if stream.is_closed() {
+                return;
+            }
+            if stream.state() == StreamState::Idle {
+                stream.discard(error);
+                return;
+            }
+            stream.handle_error(error.clone()).expect("Never fails");
+        } else if self.is_idle_stream(stream_id) {
+            return;
+        }
+        self.sink.start_write_reply_frame(frame::RstStreamFrame { stream_id, error });
+    }
+
+    /// Converts the `StreamClosed` error in `result` to a stream error.
+    ///
+    /// The other errors are treated as connection errors.
+    fn handle_stream_result(&mut self, stream_id: StreamId, result: Result<()>) -> Result<()> {
+        match result {
+            Err(e) => {
+                if let ErrorKind::StreamClosed = *e.kind() {
+                    self.stream_error(stream_id, e);
+                    Ok(())
+                } else {
+                    Err(e)
+                }
+            }
+            Ok(()) => Ok(()),
+        }
+    }
+
+    /// Sends a GOAWAY frame for the connection error `error`.
+    ///
+    /// The connection fails with `error` once the frame has been written.
+    fn terminate(&mut self, error: Error) {
+        if self.connection_error.is_some() {
+            return;
+        }
+        self.sink.start_write_frame(frame::GoawayFrame {
+            last_stream_id: self.last_peer_stream_id,
+            error: error.clone(),
+            debug_data: Vec::new(),
+        });
+        self.connection_error = Some(error);
+    }
+    fn poll_settings_timer(&mut self) -> Result<()> {
+        let is_expired = if let Some(ref mut timer) = self.settings_timer {
+            track!(timer.poll().map_err(|e| ErrorKind::InternalError.cause(e)))?
+                .is_ready()
+        } else {
+            false
+        };
+        if is_expired {
+            self.settings_timer = None;
+            let e = ErrorKind::SettingsTimeout.cause("SETTINGS frame has not been acknowledged");
+            self.terminate(track!(Error::from(e)));
+        }
+        Ok(())
+    }
+    fn is_closing(&self) -> bool {
+        if let Some(ShutdownPhase::Draining) = self.shutdown_phase {
+            true
+        } else {
+            self.goaway_last_stream_id.is_some()
+        }
+    }
+    fn is_idle_stream(&self, stream_id: StreamId) -> bool {
+        if self.is_peer_initiated_stream(stream_id) {
+            stream_id >= self.next_peer_stream_id
+        } else {
+            stream_id >= self.next_self_stream_id
+        }
+    }
+    fn handle_stream_command(&mut self, stream_id: StreamId, command: StreamCommand) {
+        let result = match command {
+            StreamCommand::SendHeaders { header, end_stream } => {
+                track!(self.send_headers(stream_id, header, end_stream))
+            }
+            StreamCommand::SendData { data, end_stream } => {
+                if let Some(stream) = self.streams.get_mut(&stream_id) {
+                    stream.take_send_credit(data.as_ref().len() as u32);
+                }
+                track!(self.send_data(stream_id, data, end_stream))
+            }
+            StreamCommand::SendTrailers(trailers) => track!(self.send_trailers(stream_id, trailers)),
+            StreamCommand::Reset(e) => {
+                self.reset_stream(stream_id, e);
+                Ok(())
+            }
+            StreamCommand::PushPromise(header, reply) => {
+                reply.exit(track!(self.push_promise(stream_id, header)));
+                Ok(())
+            }
+            StreamCommand::DataRead(size) => {
+                self.recv_unread = self.recv_unread.saturating_sub(size);
+                if let Some(stream) = self.streams.get_mut(&stream_id) {
+                    stream.mark_data_read(size);
+                }
+                self.replenish_windows(stream_id);
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            if self.streams.contains_key(&stream_id) {
+                self.stream_error(stream_id, e);
+            }
+        }
+    }
+    fn is_peer_initiated_stream(&self, stream_id: StreamId) -> bool {
+        if self.is_server {
+            stream_id.is_client_initiated_stream()
+        } else {
+            stream_id.is_server_initiated_stream()
         }
     }
     fn handle_continuation_frame(
         &mut self,
         frame: frame::ContinuationFrame<Vec<u8>>,
     ) -> Result<()> {
-        unimplemented!("{:?}", frame);
+        // > A CONTINUATION frame MUST be preceded by a HEADERS, PUSH_PROMISE or
+        // > CONTINUATION frame without the END_HEADERS flag set.  A recipient
+        // > that observes violation of this rule MUST respond with a connection
+        // > error (Section 5.4.1) of type PROTOCOL_ERROR.
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.10)
+        let mut block = track_assert_some!(
+            self.continuing_header_block.take(),
+            ErrorKind::ProtocolError
+        );
+        track_assert_eq!(frame.stream_id, block.stream_id, ErrorKind::ProtocolError);
+
+        // > A server that receives a larger header block than it is willing to handle
+        // > can send an HTTP 431 (Request Header Fields Too Large) status code
+        // > [RFC6585].  A client can discard responses that it cannot process.
+        // > The header block MUST be processed to ensure a consistent connection state,
+        // > unless the connection is closed.
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-10.5.1)
+        //
+        // Blocks decoding to a list within our `SETTINGS_MAX_HEADER_LIST_SIZE` are always
+        // processed, so that the stream alone is reset. Since a Huffman code is at most
+        // 30 bits long, such blocks never exceed four times the limit.
+        let limit = if let Some(size) = self.settings.max_header_list_size {
+            size as usize * 4
+        } else {
+            MAX_HEADER_BLOCK_SIZE
+        };
+        track_assert!(
+            block.fragment.len() + frame.payload.len() <= limit,
+            ErrorKind::EnhanceYourCalm,
+            "Too large header block: stream_id={:?}, limit={}",
+            block.stream_id,
+            limit
+        );
+        block.fragment.extend_from_slice(&frame.payload);
+        if frame.end_headers {
+            track!(self.handle_header_block(block))?;
+        } else {
+            self.continuing_header_block = Some(block);
+        }
+        Ok(())
     }
     fn handle_data_frame(&mut self, frame: frame::DataFrame<Vec<u8>>) -> Result<()> {
-        // TODO: flow control
-        if let Some(ref mut stream) = self.streams.get_mut(&frame.stream_id) {
-            stream.handle_data(frame.data);
-            if frame.end_stream {
-                stream.handle_end_stream();
+        // > The entire DATA frame payload is included in flow control,
+        // > including the Pad Length and Padding fields if present.
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.1)
+        let flow_controlled_len = frame.payload_len() as u32;
+        track!(self.recv_window.consume(flow_controlled_len))?;
+        if self.streams.contains_key(&frame.stream_id) {
+            let result = {
+                let stream = self.streams.get_mut(&frame.stream_id).expect("Never fails");
+                track!(stream.recv_window_mut().consume(flow_controlled_len))
+            };
+            if let Err(e) = result {
+                self.stream_error(frame.stream_id, e);
+            } else {
+                let (result, delivered) = {
+                    let stream = self.streams.get_mut(&frame.stream_id).expect("Never fails");
+                    let unread = stream.recv_unread();
+                    let result = track!(stream.handle_data(frame.data, frame.end_stream));
+                    (result, stream.recv_unread() - unread)
+                };
+                self.recv_unread += delivered;
+                track!(self.handle_stream_result(frame.stream_id, result))?;
             }
         } else {
+            // > idle:
+            // > ...
+            // > Receiving any frame other than HEADERS or PRIORITY on a stream in
+            // > this state MUST be treated as a connection error (Section 5.4.1)
+            // > of type PROTOCOL_ERROR.
+            // >
+            // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-5.1)
+            track_assert!(
+                !self.is_idle_stream(frame.stream_id),
+                ErrorKind::ProtocolError,
+                "stream_id={:?}",
+                frame.stream_id
+            );
+
             // > If a DATA frame is received
             // > whose stream is not in "open" or "half-closed (local)" state, the
             // > recipient MUST respond with a stream error (Section 5.4.2) of type
             // > STREAM_CLOSED.
             // >
             // > [RFC 7540]
-            unimplemented!("{:?}", frame);
+            let e = ErrorKind::StreamClosed.cause(format!("stream_id={:?}", frame.stream_id));
+            self.stream_error(frame.stream_id, track!(Error::from(e)));
         }
+        self.replenish_windows(frame.stream_id);
         Ok(())
     }
     fn handle_goaway_frame(&mut self, frame: frame::GoawayFrame) -> Result<()> {
-        unimplemented!("{:?}", frame);
+        // > Endpoints MUST NOT increase the value they send in the last stream
+        // > identifier, since the peers might already have retried unprocessed
+        // > requests on another connection.
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.8)
+        let last_stream_id = self.goaway_last_stream_id.map_or(
+            frame.last_stream_id,
+            |id| cmp::min(id, frame.last_stream_id),
+        );
+        self.goaway_last_stream_id = Some(last_stream_id);
+
+        // > The GOAWAY frame also contains the last stream identifier that the sender
+        // > of the GOAWAY frame might have acted upon or might yet act upon.
+        // > ...
+        // > Requests on stream identifiers higher than the last stream identifier
+        // > can be safely retried using a new connection.
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-8.1.4)
+        // The waiting streams have not been sent to the peer yet, so they are never admitted.
+        while let Some(stream_id) = self.waiting_streams.pop_front() {
+            if let Some(stream) = self.streams.get_mut(&stream_id) {
+                let e = ErrorKind::RefusedStream.cause(
+                    format!("GOAWAY received before the stream is opened: stream_id={:?}", stream_id),
+                );
+                stream.discard(e.into());
+            }
+        }
+
+        let is_server = self.is_server;
+        for (&stream_id, stream) in &mut self.streams {
+            let is_self_initiated = if is_server {
+                stream_id.is_server_initiated_stream()
+            } else {
+                stream_id.is_client_initiated_stream()
+            };
+            if is_self_initiated && stream_id > last_stream_id && !stream.is_closed() {
+                let e = ErrorKind::RefusedStream.cause(
+                    format!("GOAWAY received: last_stream_id={:?}", last_stream_id),
+                );
+                if stream.state() == StreamState::Idle {
+                    stream.discard(e.into());
+                } else {
+                    stream.handle_error(e.into()).expect("Never fails");
+                }
+            }
+        }
+
+        self.events.push_back(Event::GoAway {
+            last_stream_id: frame.last_stream_id,
+            error: frame.error,
+            debug_data: frame.debug_data,
+        });
+        Ok(())
     }
     fn handle_headers_frame(&mut self, frame: frame::HeadersFrame<Vec<u8>>) -> Result<()> {
-        if frame.end_stream {
-            unimplemented!("{:?}", frame);
+        let block = HeaderBlock {
+            stream_id: frame.stream_id,
+            kind: HeaderBlockKind::Headers {
+                end_stream: frame.end_stream,
+                priority: frame.priority,
+            },
+            fragment: frame.fragment,
+        };
+        if frame.end_headers {
+            track!(self.handle_header_block(block))?;
+        } else {
+            self.continuing_header_block = Some(block);
         }
-        if frame.priority.is_some() {
-            unimplemented!("{:?}", frame);
+        Ok(())
+    }
+    fn handle_header_block(&mut self, block: HeaderBlock) -> Result<()> {
+        match block.kind {
+            HeaderBlockKind::Headers {
+                end_stream,
+                priority,
+            } => track!(self.handle_headers(
+                block.stream_id,
+                end_stream,
+                priority,
+                &block.fragment,
+            )),
+            HeaderBlockKind::PushPromise { promise_stream_id } => track!(self.handle_push_promise(
+                block.stream_id,
+                promise_stream_id,
+                &block.fragment,
+            )),
         }
-        if !frame.end_headers {
-            unimplemented!("{:?}", frame);
+    }
+    fn handle_headers(
+        &mut self,
+        stream_id: StreamId,
+        end_stream: bool,
+        priority: Option<Priority>,
+        block: &[u8],
+    ) -> Result<()> {
+        let is_header_received = self.streams
+            .get(&stream_id)
+            .map(|s| s.is_header_received());
+        if let Some(is_header_received) = is_header_received {
+            // > Streams initiated by a client MUST use odd-numbered stream
+            // > identifiers; those initiated by the server MUST use even-numbered
+            // > stream identifiers.
+            // >
+            // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-5.1.1)
+            //
+            // A stream initiated by us is opened by our HEADERS frame,
+            // so the peer can not open it by sending HEADERS before that.
+            let state = self.streams[&stream_id].state();
+            track_assert!(
+                state != StreamState::Idle || self.is_peer_initiated_stream(stream_id),
+                ErrorKind::ProtocolError,
+                "HEADERS on a stream which we have not opened: stream_id={:?}",
+                stream_id
+            );
+            let header = if let Some(header) = track!(self.decode_header(stream_id, block))? {
+                header
+            } else {
+                return Ok(());
+            };
+            let kind = if is_header_received {
+                HeaderKind::Trailers
+            } else if self.is_server {
+                HeaderKind::Request
+            } else {
+                HeaderKind::Response
+            };
+            if let Err(e) = track!(header.validate(kind)) {
+                self.stream_error(stream_id, e);
+                return Ok(());
+            }
+            if kind == HeaderKind::Trailers && !end_stream {
+                // > An endpoint that receives a HEADERS frame without the END_STREAM flag
+                // > set after receiving a final (non-informational) status code MUST
+                // > treat the corresponding request or response as malformed
+                // > (Section 8.1.2.6).
+                // >
+                // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-8.1)
+                let e = ErrorKind::ProtocolError.cause("Trailers without the END_STREAM flag");
+                self.stream_error(stream_id, track!(Error::from(e)));
+                return Ok(());
+            }
+            if let Some(priority) = priority {
+                if !self.prioritize(stream_id, priority) {
+                    return Ok(());
+                }
+            }
+            let result = track!(
+                self.streams
+                    .get_mut(&stream_id)
+                    .expect("Never fails")
+                    .handle_header(header, end_stream)
+            );
+            return track!(self.handle_stream_result(stream_id, result));
         }
-        if self.streams.contains_key(&frame.stream_id) {
-            unimplemented!("{:?}", frame);
+        if !self.is_idle_stream(stream_id) {
+            // The header block must be decoded to keep the HPACK context in sync.
+            track!(Header::decode(&mut self.hpack_decoder, block))?;
+            let e = ErrorKind::StreamClosed.cause(format!("stream_id={:?}", stream_id));
+            self.stream_error(stream_id, track!(Error::from(e)));
+            return Ok(());
         }
 
         // > The identifier of a newly established stream MUST be numerically
@@ -139,23 +1192,100 @@ impl<R: Read, W: Write> Connection<R, W> {
         // >
         // > [RFC 7540]
         track_assert!(
-            frame.stream_id >= self.next_peer_stream_id,
+            self.is_peer_initiated_stream(stream_id),
+            ErrorKind::ProtocolError,
+            "stream_id={:?}",
+            stream_id
+        );
+        track_assert!(
+            stream_id >= self.next_peer_stream_id,
             ErrorKind::ProtocolError
         );
-        let header = track!(Header::decode(&mut self.hpack_decoder, &frame.fragment))?;
 
-        let (stream, mut handle) = Stream::new(frame.stream_id, self.stream_item_tx.clone());
-        track!(handle.handle_header(header))?;
+        // Servers can open streams only by reserving them with PUSH_PROMISE frames
+        // (RFC 7540#section-8.2), and such streams are not idle.
+        track_assert!(
+            self.is_server,
+            ErrorKind::ProtocolError,
+            "HEADERS on an idle server-initiated stream: stream_id={:?}",
+            stream_id
+        );
+        self.next_peer_stream_id = StreamId::new_unchecked(stream_id.as_u32() + 2);
+
+        let header = if let Some(header) = track!(self.decode_header(stream_id, block))? {
+            header
+        } else {
+            return Ok(());
+        };
+        if let Some(ShutdownPhase::Draining) = self.shutdown_phase {
+            // > Once sent, the sender will ignore frames sent on streams initiated by
+            // > the receiver if the stream has an identifier higher than the included
+            // > last stream identifier.
+            // >
+            // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.8)
+            return Ok(());
+        }
+        self.last_peer_stream_id = stream_id;
+
+        // > An endpoint that receives a HEADERS frame that causes its advertised
+        // > concurrent stream limit to be exceeded MUST treat this as a stream
+        // > error (Section 5.4.2) of type PROTOCOL_ERROR or REFUSED_STREAM.
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-5.1.2)
+        if let Some(max) = self.settings.max_concurrent_streams {
+            if self.count_peer_initiated_streams() >= max as usize {
+                let e = ErrorKind::RefusedStream.cause(format!(
+                    "Too many concurrent streams: max={}",
+                    max
+                ));
+                self.stream_error(stream_id, track!(Error::from(e)));
+                return Ok(());
+            }
+        }
+        if let Err(e) = track!(header.validate(HeaderKind::Request)) {
+            // > Malformed requests or responses that are detected MUST be treated as a
+            // > stream error (Section 5.4.2) of type PROTOCOL_ERROR.
+            // >
+            // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-8.1.2.6)
+            self.stream_error(stream_id, e);
+            return Ok(());
+        }
+        if let Some(priority) = priority {
+            if !self.prioritize(stream_id, priority) {
+                return Ok(());
+            }
+        }
 
-        self.streams.insert(frame.stream_id, handle);
+        let stream = self.new_stream(stream_id);
+        track!(
+            self.streams
+                .get_mut(&stream_id)
+                .expect("Never fails")
+                .handle_header(header, end_stream)
+        )?;
         self.events.push_back(Event::Stream(stream));
         Ok(())
     }
     fn handle_ping_frame(&mut self, frame: frame::PingFrame) -> Result<()> {
-        if frame.ack {
+        let is_shutdown_pong = if let Some(ShutdownPhase::WaitingPong(data)) = self.shutdown_phase {
+            frame.ack && frame.data == data
+        } else {
+            false
+        };
+        if is_shutdown_pong {
+            self.sink.start_write_frame(frame::GoawayFrame {
+                last_stream_id: self.last_peer_stream_id,
+                error: ErrorKind::NoError.into(),
+                debug_data: Vec::new(),
+            });
+            self.shutdown_phase = Some(ShutdownPhase::Draining);
+        } else if frame.ack {
+            if let Some(i) = self.outstanding_pings.iter().position(|d| *d == frame.data) {
+                self.outstanding_pings.remove(i);
+            }
             self.events.push_back(Event::Pong { data: frame.data });
         } else {
-            self.sink.start_write_frame(frame::PingFrame {
+            self.sink.start_write_reply_frame(frame::PingFrame {
                 ack: true,
                 data: frame.data,
             });
@@ -163,36 +1293,304 @@ impl<R: Read, W: Write> Connection<R, W> {
         Ok(())
     }
     fn handle_priority_frame(&mut self, frame: frame::PriorityFrame) -> Result<()> {
-        unimplemented!("{:?}", frame);
+        // > The PRIORITY frame can be sent on a stream in any state, though it
+        // > cannot be sent between consecutive frames that comprise a single
+        // > header block (Section 4.3).
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.3)
+        let is_idle = if let Some(stream) = self.streams.get(&frame.stream_id) {
+            stream.state() == StreamState::Idle
+        } else {
+            self.is_idle_stream(frame.stream_id)
+        };
+        if is_idle {
+            // The stream error of a self-dependent stream can not be reported by
+            // a RST_STREAM frame if the stream is idle, so it is treated as a connection error.
+            track_assert_ne!(
+                frame.stream_id,
+                frame.priority.stream_dependency,
+                ErrorKind::ProtocolError
+            );
+        }
+        self.prioritize(frame.stream_id, frame.priority);
+        Ok(())
     }
     fn handle_rst_stream_frame(&mut self, frame: frame::RstStreamFrame) -> Result<()> {
-        unimplemented!("{:?}", frame);
+        // > RST_STREAM frames MUST NOT be sent for a stream in the "idle" state.
+        // > If a RST_STREAM frame identifying an idle stream is received, the
+        // > recipient MUST treat this as a connection error (Section 5.4.1) of
+        // > type PROTOCOL_ERROR.
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.4)
+        //
+        // A locally-initiated stream is still "idle" until its HEADERS frame is sent,
+        // and `handle_error` fails with `ProtocolError` for such a stream.
+        if let Some(stream) = self.streams.get_mut(&frame.stream_id) {
+            track!(stream.handle_error(frame.error))?;
+            return Ok(());
+        }
+        track_assert!(
+            !self.is_idle_stream(frame.stream_id),
+            ErrorKind::ProtocolError,
+            "stream_id={:?}",
+            frame.stream_id
+        );
+        Ok(())
     }
     fn handle_push_promise_frame(&mut self, frame: frame::PushPromiseFrame<Vec<u8>>) -> Result<()> {
-        unimplemented!("{:?}", frame);
+        let block = HeaderBlock {
+            stream_id: frame.stream_id,
+            kind: HeaderBlockKind::PushPromise { promise_stream_id: frame.promise_stream_id },
+            fragment: frame.fragment,
+        };
+        if frame.end_headers {
+            track!(self.handle_header_block(block))?;
+        } else {
+            self.continuing_header_block = Some(block);
+        }
+        Ok(())
     }
-    fn handle_settings_frame(&mut self, frame: frame::SettingsFrame) -> Result<()> {
-        match frame {
+    fn handle_push_promise(
+        &mut self,
+        stream_id: StreamId,
+        promise_stream_id: StreamId,
+        block: &[u8],
+    ) -> Result<()> {
+        // > A client cannot push.  Thus, servers MUST treat the receipt of a
+        // > PUSH_PROMISE frame as a connection error (Section 5.4.1) of type
+        // > PROTOCOL_ERROR.
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-8.2)
+        track_assert!(!self.is_server, ErrorKind::ProtocolError);
+
+        // > PUSH_PROMISE MUST NOT be sent if the SETTINGS_ENABLE_PUSH setting of the
+        // > peer endpoint is set to 0.  An endpoint that has set this setting and
+        // > has received acknowledgement MUST treat the receipt of a PUSH_PROMISE
+        // > frame as a connection error (Section 5.4.1) of type PROTOCOL_ERROR.
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.6)
+        track_assert!(self.settings.enable_push, ErrorKind::ProtocolError);
+
+        // > A receiver MUST treat the receipt of a PUSH_PROMISE on a stream that is
+        // > neither "open" nor "half-closed (local)" as a connection error
+        // > (Section 5.4.1) of type PROTOCOL_ERROR.
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.6)
+        let parent_state = self.streams.get(&stream_id).map(|s| s.state());
+        track_assert!(
+            parent_state == Some(StreamState::Open) ||
+                parent_state == Some(StreamState::HalfClosedLocal),
+            ErrorKind::ProtocolError,
+            "stream_id={:?}, state={:?}",
+            stream_id,
+            parent_state
+        );
+
+        // > The promised stream identifier MUST be a valid choice for the
+        // > next stream sent by the sender (see "new stream identifier" in
+        // > Section 5.1.1).
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.6)
+        track_assert!(
+            self.is_peer_initiated_stream(promise_stream_id),
+            ErrorKind::ProtocolError,
+            "promise_stream_id={:?}",
+            promise_stream_id
+        );
+        track_assert!(
+            promise_stream_id >= self.next_peer_stream_id,
+            ErrorKind::ProtocolError
+        );
+        self.next_peer_stream_id = StreamId::new_unchecked(promise_stream_id.as_u32() + 2);
+
+        let header = if let Some(header) = track!(self.decode_header(promise_stream_id, block))? {
+            header
+        } else {
+            return Ok(());
+        };
+        if let Err(e) = track!(header.validate(HeaderKind::Request)) {
+            self.stream_error(promise_stream_id, e);
+            return Ok(());
+        }
+        if let Some(ShutdownPhase::Draining) = self.shutdown_phase {
+            let e = ErrorKind::RefusedStream.cause("The connection is being shut down");
+            self.stream_error(promise_stream_id, track!(Error::from(e)));
+            return Ok(());
+        }
+        if !self.push_policy.accept(stream_id, &header) {
+            let e = ErrorKind::Cancel.cause("Refused by the push policy");
+            self.stream_error(promise_stream_id, track!(Error::from(e)));
+            return Ok(());
+        }
+        self.last_peer_stream_id = promise_stream_id;
+
+        // > Pushed streams initially depend on their associated stream.
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-5.3.5)
+        let priority = Priority {
+            stream_dependency: stream_id,
+            ..Priority::default()
+        };
+        track!(self.priority_tree.set_priority(promise_stream_id, priority))?;
+
+        let stream = self.new_stream(promise_stream_id);
+        track!(
+            self.streams
+                .get_mut(&promise_stream_id)
+                .expect("Never fails")
+                .reserve_remote()
+        )?;
+        self.events.push_back(Event::Pushed {
+            parent: stream_id,
+            promised_request: header,
+            stream,
+        });
+        Ok(())
+    }
+    fn handle_settings_frame(&mut self, frame: frame::SettingsFrame) -> Result<()> {
+        match frame {
             SettingsFrame::Syn(settings) => {
                 for setting in settings {
                     track!(self.handle_setting(setting))?;
                 }
                 self.is_settings_received = true;
+                self.sink.start_write_reply_frame(SettingsFrame::Ack);
+                self.admit_waiting_streams();
+                self.flush_data();
             }
             SettingsFrame::Ack => {
-                unimplemented!("{:?}", frame);
+                let settings = track_assert_some!(
+                    self.pending_settings.pop_front(),
+                    ErrorKind::ProtocolError,
+                    "Unexpected SETTINGS ACK"
+                );
+                for setting in settings {
+                    track!(self.apply_local_setting(setting))?;
+                }
+                self.settings_timer = if self.pending_settings.is_empty() {
+                    None
+                } else {
+                    self.settings_timeout.map(timer::timeout)
+                };
             }
         }
         Ok(())
     }
+    fn apply_local_setting(&mut self, setting: Setting) -> Result<()> {
+        match setting {
+            Setting::HeaderTableSize(size) => {
+                let size = cmp::min(size, MAX_HEADER_TABLE_SIZE) as u16;
+                track!(self.hpack_decoder.set_dynamic_table_size_hard_limit(size))?;
+            }
+            Setting::InitialWindowSize(size) => {
+                let old = self.settings.initial_window_size;
+                for stream in self.streams.values_mut() {
+                    track!(stream.recv_window_mut().update_initial_window_size(old, size))?;
+                }
+            }
+            Setting::MaxFrameSize(size) => {
+                self.stream.set_max_frame_size(size);
+            }
+            Setting::EnablePush(_) |
+            Setting::MaxConcurrentStreams(_) |
+            Setting::MaxHeaderListSize(_) => {}
+        }
+        self.settings.apply(&setting);
+        Ok(())
+    }
     fn handle_window_update_frame(&mut self, frame: frame::WindowUpdateFrame) -> Result<()> {
-        unimplemented!("{:?}", frame);
+        if frame.stream_id.is_connection_control_stream() {
+            track!(self.send_window.increase(frame.window_size_increment))?;
+        } else if self.streams.contains_key(&frame.stream_id) {
+            let result = {
+                let stream = self.streams.get_mut(&frame.stream_id).expect("Never fails");
+                track!(stream.increase_send_window(frame.window_size_increment))
+            };
+            // > If a sender receives a WINDOW_UPDATE that causes a flow-control window
+            // > to exceed this maximum, it MUST terminate either the stream or the
+            // > connection, as appropriate.  For streams, the sender sends a
+            // > RST_STREAM with an error code of FLOW_CONTROL_ERROR;
+            // >
+            // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.9.1)
+            if let Err(e) = result {
+                self.stream_error(frame.stream_id, e);
+            }
+        } else {
+            // > WINDOW_UPDATE can be sent by a peer that has sent a frame bearing the
+            // > END_STREAM flag.  This means that a receiver could receive a
+            // > WINDOW_UPDATE frame on a "half-closed (remote)" or "closed" stream.
+            // > A receiver MUST NOT treat this as an error (see Section 5.1).
+            // >
+            // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.9)
+        }
+        self.flush_data();
+        Ok(())
     }
     fn handle_setting(&mut self, setting: Setting) -> Result<()> {
-        unimplemented!("{:?}", setting);
+        match setting {
+            Setting::InitialWindowSize(size) => {
+                // > When the value of SETTINGS_INITIAL_WINDOW_SIZE changes, a receiver
+                // > MUST adjust the size of all stream flow-control windows that it
+                // > maintains by the difference between the new value and the old value.
+                // >
+                // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.9.2)
+                let old = self.peer_settings.initial_window_size;
+                for stream in self.streams.values_mut() {
+                    track!(stream.update_initial_send_window_size(old, size))?;
+                }
+            }
+            Setting::HeaderTableSize(size) => {
+                // > SETTINGS_HEADER_TABLE_SIZE (0x1):  Allows the sender to inform the
+                // > remote endpoint of the maximum size of the header compression
+                // > table used to decode header blocks, in octets.  The encoder can
+                // > select any size equal to or less than this value by using
+                // > signaling specific to the header compression format inside a
+                // > header block (see [COMPRESSION]).
+                // >
+                // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.5.2)
+                //
+                // The encoder is replaced instead of calling its `set_dynamic_table_size_*`
+                // methods, because they signal the old size rather than the new one
+                // (hpack_codec v0.1.1).
+                let size = cmp::min(size, u32::from(self.max_encoder_table_size)) as u16;
+                if size != self.hpack_encoder.table().dynamic().size_hard_limit() {
+                    self.hpack_encoder = HpackEncoder::new(size);
+                    self.hpack_table_size_update = Some(size);
+                }
+            }
+            Setting::EnablePush(_) |
+            Setting::MaxConcurrentStreams(_) |
+            Setting::MaxFrameSize(_) |
+            Setting::MaxHeaderListSize(_) => {}
+        }
+        self.peer_settings.apply(&setting);
+        Ok(())
+    }
+    fn handle_extension_frame(&mut self, frame: ExtensionFrame<Vec<u8>>) -> Result<()> {
+        if let Some(handler) = self.extension_handlers.get_mut(&frame.frame_type) {
+            track!(handler.handle_frame(frame))?;
+        } else {
+            // > Implementations MUST ignore and discard any frame that has
+            // > a type that is unknown.
+            // >
+            // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-4.1)
+            self.unknown_frames += 1;
+        }
+        Ok(())
     }
     fn handle_frame(&mut self, frame: Frame<Vec<u8>>) -> Result<()> {
         println!("[DEBUG] frame: {:?}", frame);
+        if self.continuing_header_block.is_some() {
+            // > Each header block is processed as a discrete unit.  Header blocks
+            // > MUST be transmitted as a contiguous sequence of frames, with no
+            // > interleaved frames of any other type or from any other stream.
+            // >
+            // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-4.3)
+            match frame {
+                Frame::Continuation(_) => {}
+                _ => track_panic!(ErrorKind::ProtocolError, "Unexpected frame: {:?}", frame),
+            }
+        }
         match frame {
             Frame::Continuation(frame) => {
                 // TODO: エラー種別は要確認（以下同）
@@ -203,6 +1601,10 @@ impl<R: Read, W: Write> Connection<R, W> {
                 track_assert!(self.is_settings_received, ErrorKind::ProtocolError);
                 track!(self.handle_data_frame(frame))?;
             }
+            Frame::Extension(frame) => {
+                track_assert!(self.is_settings_received, ErrorKind::ProtocolError);
+                track!(self.handle_extension_frame(frame))?;
+            }
             Frame::Goaway(frame) => {
                 track_assert!(self.is_settings_received, ErrorKind::ProtocolError);
                 track!(self.handle_goaway_frame(frame))?;
@@ -247,35 +1649,1642 @@ impl<R: Read, W: Write> futures::Stream for Connection<R, W> {
                 return Ok(Async::Ready(Some(event)));
             }
 
-            track!(self.sink.poll_complete())?;
+            track!(self.poll_settings_timer())?;
+            if self.connection_error.is_some() {
+                if track!(self.sink.poll_complete())?.is_ready() {
+                    let e = self.connection_error.take().expect("Never fails");
+                    return Err(track!(e));
+                }
+                return Ok(Async::NotReady);
+            }
+
+            while let Ok(Async::Ready(Some((stream_id, command)))) =
+                futures::Stream::poll(&mut self.stream_command_rx)
+            {
+                self.handle_stream_command(stream_id, command);
+            }
+
+            let closed_streams = self.streams
+                .iter()
+                .filter(|&(_, stream)| stream.is_closed())
+                .map(|(&id, _)| id)
+                .collect::<Vec<_>>();
+            for stream_id in closed_streams {
+                self.streams.remove(&stream_id);
+                self.priority_tree.close(stream_id);
+            }
+            self.admit_waiting_streams();
+
+            let is_flushed = loop {
+                self.flush_data();
+                if self.sink.is_empty() {
+                    break true;
+                }
+                if !track!(self.sink.poll_complete())?.is_ready() {
+                    break false;
+                }
+            };
+            if self.is_closing() && self.streams.is_empty() && is_flushed {
+                return Ok(Async::Ready(None));
+            }
 
-            // TODO: handle errors and send goaway message if needed.
-            match track!(futures::Stream::poll(&mut self.stream))? {
-                Async::Ready(Some(frame)) => {
-                    track!(self.handle_frame(frame))?;
+            // > An endpoint that encounters a connection error SHOULD first send a
+            // > GOAWAY frame (Section 6.8) with the stream identifier of the last
+            // > stream that it successfully received from its peer.
+            // >
+            // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-5.4.1)
+            let polled = track!(futures::Stream::poll(&mut self.stream));
+            match polled {
+                Err(e) => self.terminate(e),
+                Ok(Async::Ready(Some(frame))) => {
+                    if let Err(e) = track!(self.handle_frame(frame)) {
+                        self.terminate(e);
+                    } else if self.sink.reply_len() > self.max_buffered_frames {
+                        // Only the frames sent in reply to the peer are counted,
+                        // since the peer can not be blamed for the frames we generated ourselves.
+                        let e = ErrorKind::EnhanceYourCalm.cause(format!(
+                            "Too many buffered reply frames: {}",
+                            self.sink.reply_len()
+                        ));
+                        self.terminate(track!(Error::from(e)));
+                    }
                 }
-                Async::Ready(None) => return Ok(Async::Ready(None)),
-                Async::NotReady => break,
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(None)),
+                Ok(Async::NotReady) => break,
             }
         }
         Ok(Async::NotReady)
     }
 }
 
+/// Encodes a dynamic table size update (RFC 7541#section-6.3) into `buf`.
+///
+/// ```text
+///      0   1   2   3   4   5   6   7
+///    +---+---+---+---+---+---+---+---+
+///    | 0 | 0 | 1 |   Max size (5+)   |
+///    +---+---------------------------+
+/// ```
+fn encode_dynamic_table_size_update(size: u16, buf: &mut Vec<u8>) {
+    const PREFIX_MAX: u16 = (1 << 5) - 1;
+    if size < PREFIX_MAX {
+        buf.push(0b0010_0000 | size as u8);
+    } else {
+        buf.push(0b0010_0000 | PREFIX_MAX as u8);
+        let mut rest = size - PREFIX_MAX;
+        while rest >= 128 {
+            buf.push((rest % 128) as u8 | 0b1000_0000);
+            rest /= 128;
+        }
+        buf.push(rest as u8);
+    }
+}
+
+#[derive(Debug)]
+enum ShutdownPhase {
+    /// Waiting for the acknowledgement of the PING frame carrying the given payload.
+    WaitingPong([u8; 8]),
+    Draining,
+}
+
+/// A header block which may be split into multiple frames.
+#[derive(Debug)]
+struct HeaderBlock {
+    stream_id: StreamId,
+    kind: HeaderBlockKind,
+    fragment: Vec<u8>,
+}
+
+#[derive(Debug)]
+enum HeaderBlockKind {
+    Headers {
+        end_stream: bool,
+        priority: Option<Priority>,
+    },
+    PushPromise { promise_stream_id: StreamId },
+}
+
+/// Builder for `Connection`.
+#[derive(Debug, Clone)]
+pub struct ConnectionBuilder {
+    settings: Settings,
+    settings_timeout: Option<Duration>,
+    encoder_table_size: u16,
+    max_buffered_frames: usize,
+    max_send_queue_size: u32,
+    connection_window_size: u32,
+    replenish_policy: Arc<ReplenishPolicy>,
+    push_policy: Arc<PushPolicy>,
+}
+impl ConnectionBuilder {
+    pub fn new() -> Self {
+        ConnectionBuilder {
+            settings: Settings::default(),
+            settings_timeout: Some(Duration::from_secs(DEFAULT_SETTINGS_TIMEOUT_SECS)),
+            encoder_table_size: Settings::default().header_table_size as u16,
+            max_buffered_frames: DEFAULT_MAX_BUFFERED_FRAMES,
+            max_send_queue_size: DEFAULT_MAX_SEND_QUEUE_SIZE,
+            connection_window_size: Settings::default().initial_window_size,
+            replenish_policy: Arc::new(ReplenishOnHalfWindow),
+            push_policy: Arc::new(AcceptAllPushes),
+        }
+    }
+
+    /// Sets `SETTINGS_HEADER_TABLE_SIZE`, the maximum size of the HPACK decoder table.
+    ///
+    /// The default value is `4096`.
+    ///
+    /// # Errors
+    ///
+    /// If `size` exceeds `65535`, an error will be returned.
+    pub fn header_table_size(&mut self, size: u32) -> Result<&mut Self> {
+        track_assert!(
+            size <= MAX_HEADER_TABLE_SIZE,
+            ErrorKind::InternalError,
+            "size={}",
+            size
+        );
+        self.settings.header_table_size = size;
+        Ok(self)
+    }
+
+    /// Sets the maximum size of the HPACK encoder table.
+    ///
+    /// The encoder uses the smaller of this and the peer's `SETTINGS_HEADER_TABLE_SIZE`.
+    ///
+    /// The default value is `4096`.
+    pub fn encoder_table_size(&mut self, size: u16) -> &mut Self {
+        self.encoder_table_size = size;
+        self
+    }
+
+    /// Sets `SETTINGS_ENABLE_PUSH`.
+    ///
+    /// The default value is `true`.
+    pub fn enable_push(&mut self, enable: bool) -> &mut Self {
+        self.settings.enable_push = enable;
+        self
+    }
+
+    /// Sets `SETTINGS_MAX_CONCURRENT_STREAMS`.
+    ///
+    /// The default value is `None` (i.e., unlimited).
+    pub fn max_concurrent_streams(&mut self, max: Option<u32>) -> &mut Self {
+        self.settings.max_concurrent_streams = max;
+        self
+    }
+
+    /// Sets `SETTINGS_INITIAL_WINDOW_SIZE`.
+    ///
+    /// The default value is `65535`.
+    ///
+    /// # Errors
+    ///
+    /// If `size` exceeds `2^31-1`, an error will be returned.
+    pub fn initial_window_size(&mut self, size: u32) -> Result<&mut Self> {
+        track_assert!(
+            size <= MAX_FLOW_CONTROL_WINDOW_SIZE,
+            ErrorKind::InternalError,
+            "size={}",
+            size
+        );
+        self.settings.initial_window_size = size;
+        Ok(self)
+    }
+
+    /// Sets the size of the connection-level receive window.
+    ///
+    /// The window starts with `65535` octets as required by HTTP/2,
+    /// and a WINDOW_UPDATE frame is sent at the beginning of the connection if `size` is larger.
+    /// After that, the window is replenished up to `size` as DATA is read by the application.
+    ///
+    /// The default value is `65535`.
+    ///
+    /// # Errors
+    ///
+    /// If `size` exceeds `2^31-1`, an error will be returned.
+    pub fn connection_window_size(&mut self, size: u32) -> Result<&mut Self> {
+        track_assert!(
+            size <= MAX_FLOW_CONTROL_WINDOW_SIZE,
+            ErrorKind::InternalError,
+            "size={}",
+            size
+        );
+        self.connection_window_size = size;
+        Ok(self)
+    }
+
+    /// Sets `SETTINGS_MAX_FRAME_SIZE`.
+    ///
+    /// The default value is `16384`.
+    ///
+    /// # Errors
+    ///
+    /// If `size` is not in the range from `2^14` to `2^24-1`, an error will be returned.
+    pub fn max_frame_size(&mut self, size: u32) -> Result<&mut Self> {
+        track_assert!(1 << 14 <= size, ErrorKind::InternalError, "size={}", size);
+        track_assert!(size < 1 << 24, ErrorKind::InternalError, "size={}", size);
+        self.settings.max_frame_size = size;
+        Ok(self)
+    }
+
+    /// Sets `SETTINGS_MAX_HEADER_LIST_SIZE`.
+    ///
+    /// The default value is `None` (i.e., unlimited).
+    pub fn max_header_list_size(&mut self, max: Option<u32>) -> &mut Self {
+        self.settings.max_header_list_size = max;
+        self
+    }
+
+    /// Sets the deadline within which the peer must acknowledge our SETTINGS frames.
+    ///
+    /// The default value is 10 seconds.
+    pub fn settings_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.settings_timeout = timeout;
+        self
+    }
+
+    /// Sets the maximum number of frames sent in reply to the peer
+    /// (i.e., acknowledgements of SETTINGS and PING, and RST_STREAM)
+    /// which can be buffered in the outgoing queue.
+    ///
+    /// If a peer makes the queue exceed this limit (e.g., by flooding PING frames),
+    /// the connection fails with `ENHANCE_YOUR_CALM`.
+    /// The frames generated by this endpoint itself (e.g., DATA) are not counted.
+    ///
+    /// The default value is `1024`.
+    pub fn max_buffered_frames(&mut self, max: usize) -> &mut Self {
+        self.max_buffered_frames = max;
+        self
+    }
+
+    /// Sets the maximum number of octets of DATA which can be queued in each stream.
+    ///
+    /// A `Stream` accepts DATA only while its queue is shorter than
+    /// both this limit and the send window of the stream.
+    /// Otherwise `futures::Sink::start_send` is not ready and `Stream::send_data` fails.
+    ///
+    /// The default value is `1048576`.
+    pub fn max_send_queue_size(&mut self, max: u32) -> &mut Self {
+        self.max_send_queue_size = max;
+        self
+    }
+
+    /// Sets the policy which decides when to send WINDOW_UPDATE frames.
+    ///
+    /// The default policy is `ReplenishOnHalfWindow`.
+    pub fn replenish_policy<P>(&mut self, policy: P) -> &mut Self
+    where
+        P: ReplenishPolicy + 'static,
+    {
+        self.replenish_policy = Arc::new(policy);
+        self
+    }
+
+    /// Sets the policy which decides whether streams pushed by the server are accepted.
+    ///
+    /// The default policy is `AcceptAllPushes`.
+    pub fn push_policy<P>(&mut self, policy: P) -> &mut Self
+    where
+        P: PushPolicy + 'static,
+    {
+        self.push_policy = Arc::new(policy);
+        self
+    }
+
+    /// Accepts a connection from a client.
+    pub fn accept<R: Read, W: Write>(&self, reader: R, writer: W) -> Accept<R, W> {
+        Accept {
+            future: preface::read_preface(reader),
+            writer: Some(writer),
+            builder: self.clone(),
+        }
+    }
+
+    /// Connects to a server.
+    pub fn connect<R: Read, W: Write>(&self, reader: R, writer: W) -> Connect<R, W> {
+        Connect {
+            future: preface::write_preface(writer),
+            reader: Some(reader),
+            builder: self.clone(),
+        }
+    }
+}
+impl Default for ConnectionBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[derive(Debug)]
 pub struct Accept<R, W> {
     future: ReadPreface<R>,
     writer: Option<W>,
+    builder: ConnectionBuilder,
 }
 impl<R: Read, W: Write> Future for Accept<R, W> {
     type Item = Connection<R, W>;
     type Error = Error;
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         if let Async::Ready(reader) = track!(self.future.poll())? {
-            let connection = Connection::new(reader, self.writer.take().expect("Never fails"));
+            let writer = self.writer.take().expect("Never fails");
+            let connection = Connection::new(reader, writer, true, &self.builder);
+            Ok(Async::Ready(connection))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Connect<R, W> {
+    future: WritePreface<W>,
+    reader: Option<R>,
+    builder: ConnectionBuilder,
+}
+impl<R: Read, W: Write> Future for Connect<R, W> {
+    type Item = Connection<R, W>;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::Ready(writer) = track!(self.future.poll())? {
+            let reader = self.reader.take().expect("Never fails");
+            let connection = Connection::new(reader, writer, false, &self.builder);
             Ok(Async::Ready(connection))
         } else {
             Ok(Async::NotReady)
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::cell::{Cell, RefCell};
+    use std::io;
+    use std::rc::Rc;
+    use std::sync::Mutex;
+    use std::thread;
+    use futures::Stream as FuturesStream;
+    use super::*;
+    use preface::PREFACE_BYTES;
+    use priority::Weight;
+    use push::RefuseAllPushes;
+    use stream::StreamItem;
+
+    /// In-memory byte channel shared between a `Connection` and a test.
+    ///
+    /// Reading from an empty pipe into a non-empty buffer fails with `WouldBlock`.
+    /// Writing into a pipe fails with `WouldBlock` while the pipe is blocked.
+    #[derive(Debug, Clone, Default)]
+    struct Pipe(Rc<RefCell<Vec<u8>>>, Rc<Cell<bool>>);
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut inner = self.0.borrow_mut();
+            if inner.is_empty() && !buf.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let size = cmp::min(buf.len(), inner.len());
+            buf[..size].copy_from_slice(&inner[..size]);
+            inner.drain(..size);
+            Ok(size)
+        }
+    }
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.1.get() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// The remote endpoint of a `Connection` under test.
+    struct Peer {
+        input: Pipe,
+        output: Pipe,
+        encoder: HpackEncoder,
+    }
+    impl Peer {
+        fn new() -> Self {
+            Peer {
+                input: Pipe::default(),
+                output: Pipe::default(),
+                encoder: HpackEncoder::new(4096),
+            }
+        }
+
+        /// Sends `frame` to the connection.
+        fn send<F: Into<Frame<Vec<u8>>>>(&self, frame: F) {
+            let bytes = track_try_unwrap!(frame.into().write_into(Vec::new()).wait());
+            self.input.0.borrow_mut().extend(bytes);
+        }
+
+        /// Takes the frames written by the connection.
+        fn recv(&self) -> Vec<Frame<Vec<u8>>> {
+            let bytes = self.output.0.borrow_mut().split_off(0);
+            let mut input = &bytes[..];
+            let mut frames = Vec::new();
+            while !input.is_empty() {
+                let (rest, frame) = track_try_unwrap!(Frame::read_from(input, 0xFF_FFFF).wait());
+                input = rest;
+                frames.push(frame);
+            }
+            frames
+        }
+
+        fn encode(&mut self, fields: &[(&str, &str)]) -> Vec<u8> {
+            track_try_unwrap!(header(fields).encode(&mut self.encoder))
+        }
+        fn send_headers(&mut self, stream_id: u8, fields: &[(&str, &str)], end_stream: bool) {
+            let fragment = self.encode(fields);
+            self.send(frame::HeadersFrame {
+                stream_id: stream_id.into(),
+                end_stream,
+                end_headers: true,
+                priority: None,
+                padding_len: None,
+                fragment,
+            });
+        }
+        fn send_push_promise(&mut self, stream_id: u8, promise_stream_id: u8) {
+            let fragment = self.encode(REQUEST);
+            self.send(frame::PushPromiseFrame {
+                stream_id: stream_id.into(),
+                promise_stream_id: promise_stream_id.into(),
+                end_headers: true,
+                padding_len: None,
+                fragment,
+            });
+        }
+    }
+
+    fn header(fields: &[(&str, &str)]) -> Header {
+        let mut header = Header::new();
+        for &(name, value) in fields {
+            header.add_field(name.as_bytes(), value.as_bytes());
+        }
+        header
+    }
+
+    const REQUEST: &[(&str, &str)] = &[(":method", "GET"), (":scheme", "https"), (":path", "/")];
+    const RESPONSE: &[(&str, &str)] = &[(":status", "200")];
+
+    /// Makes a server connection whose SETTINGS have been exchanged with `Peer`.
+    fn server(builder: &ConnectionBuilder) -> (Connection<Pipe, Pipe>, Peer) {
+        let peer = Peer::new();
+        peer.input.0.borrow_mut().extend_from_slice(&PREFACE_BYTES);
+        let future = builder.accept(peer.input.clone(), peer.output.clone());
+        let mut connection = track_try_unwrap!(future.wait());
+        handshake(&mut connection, &peer);
+        (connection, peer)
+    }
+
+    /// Makes a client connection whose SETTINGS have been exchanged with `Peer`.
+    fn client(builder: &ConnectionBuilder) -> (Connection<Pipe, Pipe>, Peer) {
+        let peer = Peer::new();
+        let future = builder.connect(peer.input.clone(), peer.output.clone());
+        let mut connection = track_try_unwrap!(future.wait());
+        let preface = peer.output.0.borrow_mut().drain(..PREFACE_BYTES.len()).collect::<Vec<_>>();
+        assert_eq!(preface, PREFACE_BYTES);
+        handshake(&mut connection, &peer);
+        (connection, peer)
+    }
+
+    fn handshake(connection: &mut Connection<Pipe, Pipe>, peer: &Peer) {
+        peer.send(SettingsFrame::Syn(Vec::new()));
+        assert!(track_try_unwrap!(run(connection)).is_empty());
+        let frames = peer.recv();
+        assert!(is_settings(&frames[0], false), "{:?}", frames);
+        assert!(is_settings(&frames[1], true), "{:?}", frames);
+        peer.send(SettingsFrame::Ack);
+        assert!(track_try_unwrap!(run(connection)).is_empty());
+    }
+
+    /// Makes a client connection which has sent a request on the stream 1.
+    fn client_with_request(builder: &ConnectionBuilder) -> (Connection<Pipe, Pipe>, Peer, Stream) {
+        let (mut client, peer) = client(builder);
+        let mut stream = track_try_unwrap!(client.open_stream());
+        track_try_unwrap!(stream.send_headers(header(REQUEST), true));
+        track_try_unwrap!(run(&mut client));
+        assert_eq!(peer.recv().len(), 1);
+        (client, peer, stream)
+    }
+
+    /// Polls `connection` until it gets blocked, and returns the resulting events.
+    fn run(connection: &mut Connection<Pipe, Pipe>) -> Result<Vec<Event>> {
+        let mut events = Vec::new();
+        while let Async::Ready(Some(event)) = track!(connection.poll())? {
+            events.push(event);
+        }
+        Ok(events)
+    }
+
+    /// Polls `connection` until it fails with a connection error.
+    fn run_until_error(connection: &mut Connection<Pipe, Pipe>) -> Error {
+        match run(connection) {
+            Ok(events) => panic!("{:?}", events),
+            Err(e) => e,
+        }
+    }
+
+    fn is_settings(frame: &Frame<Vec<u8>>, ack: bool) -> bool {
+        if let Frame::Settings(ref frame) = *frame {
+            frame.is_ack() == ack
+        } else {
+            false
+        }
+    }
+    fn goaway_code(frame: &Frame<Vec<u8>>) -> Option<u32> {
+        if let Frame::Goaway(ref frame) = *frame {
+            Some(frame.error.as_code())
+        } else {
+            None
+        }
+    }
+
+    fn goaway_last_stream_id(frame: &Frame<Vec<u8>>) -> Option<u32> {
+        if let Frame::Goaway(ref frame) = *frame {
+            Some(frame.last_stream_id.as_u32())
+        } else {
+            None
+        }
+    }
+    fn ping_data(frame: &Frame<Vec<u8>>) -> Option<[u8; 8]> {
+        if let Frame::Ping(ref frame) = *frame {
+            Some(frame.data)
+        } else {
+            None
+        }
+    }
+    fn window_update(frame: &Frame<Vec<u8>>) -> Option<(u32, u32)> {
+        if let Frame::WindowUpdate(ref frame) = *frame {
+            Some((frame.stream_id.as_u32(), frame.window_size_increment))
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn server_accepts_requests() {
+        let (mut server, mut peer) = server(&ConnectionBuilder::new());
+        peer.send_headers(1, REQUEST, true);
+        let events = track_try_unwrap!(run(&mut server));
+        assert_eq!(events.len(), 1);
+        if let Event::Stream(ref stream) = events[0] {
+            assert_eq!(stream.id(), StreamId::from(1u8));
+        } else {
+            panic!("{:?}", events);
+        }
+    }
+
+    #[test]
+    fn client_rejects_headers_on_idle_server_initiated_streams() {
+        let (mut client, mut peer) = client(&ConnectionBuilder::new());
+        peer.send_headers(2, REQUEST, true);
+        let e = run_until_error(&mut client);
+        assert_eq!(e.as_code(), 0x1);
+
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        assert_eq!(goaway_code(&frames[0]), Some(0x1));
+    }
+
+    #[test]
+    fn client_rejects_headers_on_streams_it_has_not_opened() {
+        let (mut client, mut peer) = client(&ConnectionBuilder::new());
+        peer.send(SettingsFrame::Syn(vec![Setting::MaxConcurrentStreams(1)]));
+        track_try_unwrap!(run(&mut client));
+        assert!(is_settings(&peer.recv()[0], true));
+
+        // The stream 3 waits for the stream 1 to be closed.
+        let mut first = track_try_unwrap!(client.open_stream());
+        let mut second = track_try_unwrap!(client.open_stream());
+        track_try_unwrap!(first.send_headers(header(REQUEST), false));
+        track_try_unwrap!(second.send_headers(header(REQUEST), false));
+        track_try_unwrap!(run(&mut client));
+        assert_eq!(peer.recv().len(), 1);
+
+        peer.send_headers(3, RESPONSE, false);
+        let e = run_until_error(&mut client);
+        assert_eq!(e.as_code(), 0x1);
+
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        assert_eq!(goaway_code(&frames[0]), Some(0x1));
+    }
+
+    #[test]
+    fn windows_are_replenished_when_data_is_read() {
+        let (mut server, mut peer) = server(&ConnectionBuilder::new());
+        peer.send_headers(1, REQUEST, false);
+        for _ in 0..3 {
+            peer.send(frame::DataFrame {
+                stream_id: StreamId::from(1u8),
+                end_stream: false,
+                padding_len: None,
+                data: vec![0; 16_384],
+            });
+        }
+        let mut events = track_try_unwrap!(run(&mut server));
+        let mut stream = match events.pop() {
+            Some(Event::Stream(stream)) => stream,
+            event => panic!("{:?}", event),
+        };
+        assert!(peer.recv().is_empty());
+
+        // header and the first DATA frame
+        for _ in 0..2 {
+            assert!(track_try_unwrap!(stream.poll()).is_ready());
+        }
+        track_try_unwrap!(run(&mut server));
+        assert!(peer.recv().is_empty());
+
+        // the second DATA frame
+        assert!(track_try_unwrap!(stream.poll()).is_ready());
+        track_try_unwrap!(run(&mut server));
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 2, "{:?}", frames);
+        assert_eq!(window_update(&frames[0]), Some((0, 32_768)));
+        assert_eq!(window_update(&frames[1]), Some((1, 32_768)));
+    }
+
+    #[test]
+    fn connection_window_is_replenished_up_to_the_configured_size() {
+        let mut builder = ConnectionBuilder::new();
+        track_try_unwrap!(builder.connection_window_size(131_072));
+        let mut peer = Peer::new();
+        peer.input.0.borrow_mut().extend_from_slice(&PREFACE_BYTES);
+        let future = builder.accept(peer.input.clone(), peer.output.clone());
+        let mut server = track_try_unwrap!(future.wait());
+        track_try_unwrap!(run(&mut server));
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 2, "{:?}", frames);
+        assert!(is_settings(&frames[0], false), "{:?}", frames);
+        assert_eq!(window_update(&frames[1]), Some((0, 131_072 - 65_535)));
+        peer.send(SettingsFrame::Syn(Vec::new()));
+        peer.send(SettingsFrame::Ack);
+        track_try_unwrap!(run(&mut server));
+        assert!(is_settings(&peer.recv()[0], true));
+
+        // Five streams consume more than the default connection-level window.
+        let mut streams = Vec::new();
+        for i in 0..5 {
+            let stream_id = 2 * i + 1;
+            peer.send_headers(stream_id, REQUEST, false);
+            peer.send(frame::DataFrame {
+                stream_id: StreamId::from(stream_id),
+                end_stream: false,
+                padding_len: None,
+                data: vec![0; 16_384],
+            });
+        }
+        for event in track_try_unwrap!(run(&mut server)) {
+            if let Event::Stream(stream) = event {
+                streams.push(stream);
+            } else {
+                panic!("{:?}", event);
+            }
+        }
+        assert_eq!(streams.len(), 5);
+        assert!(peer.recv().is_empty());
+
+        for stream in &mut streams {
+            for _ in 0..2 {
+                assert!(track_try_unwrap!(stream.poll()).is_ready());
+            }
+        }
+        track_try_unwrap!(run(&mut server));
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        assert_eq!(window_update(&frames[0]), Some((0, 4 * 16_384)));
+    }
+
+    #[test]
+    fn trailers_are_received_after_data() {
+        let (mut server, mut peer) = server(&ConnectionBuilder::new());
+        peer.send_headers(1, REQUEST, false);
+        peer.send(frame::DataFrame {
+            stream_id: StreamId::from(1u8),
+            end_stream: false,
+            padding_len: None,
+            data: b"foo".to_vec(),
+        });
+        peer.send_headers(1, &[("grpc-status", "0")], true);
+        let mut events = track_try_unwrap!(run(&mut server));
+        let stream = match events.pop() {
+            Some(Event::Stream(stream)) => stream,
+            event => panic!("{:?}", event),
+        };
+        assert!(peer.recv().is_empty());
+
+        let mut items = FuturesStream::wait(stream);
+        match items.next() {
+            Some(Ok(StreamItem::Header(h))) => assert_eq!(h.get(b":path"), Some(&b"/"[..])),
+            item => panic!("{:?}", item),
+        }
+        match items.next() {
+            Some(Ok(StreamItem::Data(d))) => assert_eq!(d.as_ref(), b"foo"),
+            item => panic!("{:?}", item),
+        }
+        match items.next() {
+            Some(Ok(StreamItem::Trailers(t))) => assert_eq!(t.get(b"grpc-status"), Some(&b"0"[..])),
+            item => panic!("{:?}", item),
+        }
+        assert!(items.next().is_none());
+    }
+
+    #[test]
+    fn trailers_are_sent_after_all_the_queued_data() {
+        let (mut client, mut peer) = client(&ConnectionBuilder::new());
+        peer.send(SettingsFrame::Syn(vec![Setting::InitialWindowSize(5)]));
+        track_try_unwrap!(run(&mut client));
+        assert!(is_settings(&peer.recv()[0], true));
+
+        let mut stream = track_try_unwrap!(client.open_stream());
+        track_try_unwrap!(stream.send_headers(header(REQUEST), false));
+        track_try_unwrap!(stream.send_data(Bytes::new(b"0123456789"), false));
+        track_try_unwrap!(stream.send_trailers(header(&[("grpc-status", "0")])));
+        track_try_unwrap!(run(&mut client));
+
+        // The trailers are held back while the data is blocked by the send window.
+        let mut frames = peer.recv();
+        assert_eq!(frames.len(), 2, "{:?}", frames);
+
+        peer.send(frame::WindowUpdateFrame {
+            stream_id: StreamId::from(1u8),
+            window_size_increment: 5,
+        });
+        track_try_unwrap!(run(&mut client));
+        frames.extend(peer.recv());
+        assert_eq!(frames.len(), 4, "{:?}", frames);
+
+        let mut data = Vec::new();
+        for frame in &frames[1..3] {
+            if let Frame::Data(ref frame) = *frame {
+                assert!(!frame.end_stream);
+                data.extend_from_slice(&frame.data);
+            } else {
+                panic!("{:?}", frames);
+            }
+        }
+        assert_eq!(data, b"0123456789");
+        if let Frame::Headers(ref frame) = frames[3] {
+            assert_eq!(frame.stream_id, StreamId::from(1u8));
+            assert!(frame.end_stream);
+            let mut decoder = HpackDecoder::new(4096);
+            let trailers = track_try_unwrap!(Header::decode(&mut decoder, &frame.fragment));
+            assert_eq!(trailers.get(b"grpc-status"), Some(&b"0"[..]));
+        } else {
+            panic!("{:?}", frames);
+        }
+    }
+
+    #[test]
+    fn header_blocks_are_reassembled_from_continuation_frames() {
+        let (mut server, mut peer) = server(&ConnectionBuilder::new());
+        let mut fragment = peer.encode(REQUEST);
+        let rest = fragment.split_off(3);
+        peer.send(frame::HeadersFrame {
+            stream_id: StreamId::from(1u8),
+            end_stream: true,
+            end_headers: false,
+            priority: None,
+            padding_len: None,
+            fragment,
+        });
+        peer.send(frame::ContinuationFrame {
+            stream_id: StreamId::from(1u8),
+            end_headers: true,
+            payload: rest,
+        });
+        let mut events = track_try_unwrap!(run(&mut server));
+        let stream = match events.pop() {
+            Some(Event::Stream(stream)) => stream,
+            event => panic!("{:?}", event),
+        };
+        match FuturesStream::wait(stream).next() {
+            Some(Ok(StreamItem::Header(h))) => assert_eq!(h.get(b":path"), Some(&b"/"[..])),
+            item => panic!("{:?}", item),
+        }
+    }
+
+    #[test]
+    fn frames_interleaved_in_header_blocks_are_rejected() {
+        let (mut server, mut peer) = server(&ConnectionBuilder::new());
+        let fragment = peer.encode(REQUEST);
+        peer.send(frame::HeadersFrame {
+            stream_id: StreamId::from(1u8),
+            end_stream: true,
+            end_headers: false,
+            priority: None,
+            padding_len: None,
+            fragment,
+        });
+        peer.send(frame::PingFrame {
+            ack: false,
+            data: [0; 8],
+        });
+        let e = run_until_error(&mut server);
+        assert_eq!(e.as_code(), 0x1);
+
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        assert_eq!(goaway_code(&frames[0]), Some(0x1));
+    }
+
+    #[test]
+    fn too_large_header_blocks_are_rejected() {
+        let (mut server, mut peer) = server(&ConnectionBuilder::new());
+        let fragment = peer.encode(REQUEST);
+        peer.send(frame::HeadersFrame {
+            stream_id: StreamId::from(1u8),
+            end_stream: true,
+            end_headers: false,
+            priority: None,
+            padding_len: None,
+            fragment,
+        });
+        for _ in 0..(MAX_HEADER_BLOCK_SIZE / 16_384) {
+            peer.send(frame::ContinuationFrame {
+                stream_id: StreamId::from(1u8),
+                end_headers: false,
+                payload: vec![0; 16_384],
+            });
+        }
+        let e = run_until_error(&mut server);
+        assert_eq!(e.as_code(), 0xb);
+
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        assert_eq!(goaway_code(&frames[0]), Some(0xb));
+    }
+
+    #[test]
+    fn header_blocks_are_limited_by_max_header_list_size() {
+        let mut builder = ConnectionBuilder::new();
+        builder.max_header_list_size(Some(1024));
+        let (mut server, mut peer) = server(&builder);
+        let fragment = peer.encode(REQUEST);
+        peer.send(frame::HeadersFrame {
+            stream_id: StreamId::from(1u8),
+            end_stream: true,
+            end_headers: false,
+            priority: None,
+            padding_len: None,
+            fragment,
+        });
+        for _ in 0..4 {
+            peer.send(frame::ContinuationFrame {
+                stream_id: StreamId::from(1u8),
+                end_headers: false,
+                payload: vec![0; 1024],
+            });
+        }
+        let e = run_until_error(&mut server);
+        assert_eq!(e.as_code(), 0xb);
+
+        let frames = peer.recv();
+        assert_eq!(goaway_code(frames.last().unwrap()), Some(0xb));
+    }
+
+    #[test]
+    fn too_large_header_lists_reset_only_the_stream() {
+        let mut builder = ConnectionBuilder::new();
+        builder.max_header_list_size(Some(128));
+        let (mut server, mut peer) = server(&builder);
+        let value = "a".repeat(128);
+        let mut fields = REQUEST.to_vec();
+        fields.push(("x-large", &value));
+        peer.send_headers(1, &fields, true);
+        peer.send_headers(3, REQUEST, true);
+        let events = track_try_unwrap!(run(&mut server));
+        assert_eq!(events.len(), 1, "{:?}", events);
+        if let Event::Stream(ref stream) = events[0] {
+            assert_eq!(stream.id(), StreamId::from(3u8));
+        } else {
+            panic!("{:?}", events);
+        }
+
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        if let Frame::RstStream(ref frame) = frames[0] {
+            assert_eq!(frame.stream_id, StreamId::from(1u8));
+            assert_eq!(frame.error.as_code(), 0x1);
+        } else {
+            panic!("{:?}", frames);
+        }
+    }
+
+    #[test]
+    fn ping_floods_are_rejected() {
+        let mut builder = ConnectionBuilder::new();
+        builder.max_buffered_frames(4);
+        let (mut server, peer) = server(&builder);
+        peer.output.1.set(true);
+        for i in 0..8 {
+            peer.send(frame::PingFrame {
+                ack: false,
+                data: [i; 8],
+            });
+        }
+        track_try_unwrap!(run(&mut server));
+
+        // The connection error is reported after the GOAWAY frame has been written.
+        peer.output.1.set(false);
+        let e = run_until_error(&mut server);
+        assert_eq!(e.as_code(), 0xb);
+        let frames = peer.recv();
+        assert_eq!(goaway_code(frames.last().unwrap()), Some(0xb));
+    }
+
+    #[test]
+    fn self_generated_frames_are_not_counted_as_buffered_frames() {
+        let mut builder = ConnectionBuilder::new();
+        builder.max_buffered_frames(2);
+        let (mut server, peer) = server(&builder);
+        peer.output.1.set(true);
+        for i in 0..8 {
+            server.ping([i; 8]);
+        }
+        peer.send(frame::PingFrame {
+            ack: false,
+            data: [0xFF; 8],
+        });
+        track_try_unwrap!(run(&mut server));
+
+        peer.output.1.set(false);
+        track_try_unwrap!(run(&mut server));
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 9, "{:?}", frames);
+        assert_eq!(ping_data(&frames[8]), Some([0xFF; 8]));
+    }
+
+    #[test]
+    fn shutdown_sends_goaway_frames_in_two_phases() {
+        let (mut server, mut peer) = server(&ConnectionBuilder::new());
+        peer.send_headers(1, REQUEST, true);
+        track_try_unwrap!(run(&mut server));
+
+        server.ping([0; 8]);
+        server.shutdown();
+        track_try_unwrap!(run(&mut server));
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 3, "{:?}", frames);
+        assert_eq!(ping_data(&frames[0]), Some([0; 8]));
+        assert_eq!(goaway_last_stream_id(&frames[1]), Some(0x7FFF_FFFF));
+        let shutdown_ping_data = ping_data(&frames[2]).expect("PING");
+        assert_ne!(shutdown_ping_data, [0; 8]);
+
+        // The acknowledgement of the application's PING does not advance the shutdown.
+        peer.send(frame::PingFrame {
+            ack: true,
+            data: [0; 8],
+        });
+        let events = track_try_unwrap!(run(&mut server));
+        assert_eq!(events.len(), 1, "{:?}", events);
+        if let Event::Pong { data } = events[0] {
+            assert_eq!(data, [0; 8]);
+        } else {
+            panic!("{:?}", events);
+        }
+        assert!(peer.recv().is_empty());
+
+        peer.send(frame::PingFrame {
+            ack: true,
+            data: shutdown_ping_data,
+        });
+        track_try_unwrap!(run(&mut server));
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        assert_eq!(goaway_last_stream_id(&frames[0]), Some(1));
+        assert_eq!(goaway_code(&frames[0]), Some(0x0));
+    }
+
+    #[test]
+    fn waiting_streams_are_refused_on_goaway() {
+        let (mut client, peer) = client(&ConnectionBuilder::new());
+        peer.send(SettingsFrame::Syn(vec![Setting::MaxConcurrentStreams(1)]));
+        track_try_unwrap!(run(&mut client));
+        assert!(is_settings(&peer.recv()[0], true));
+
+        let mut admitted = track_try_unwrap!(client.open_stream());
+        let mut waiting = track_try_unwrap!(client.open_stream());
+        track_try_unwrap!(admitted.send_headers(header(REQUEST), true));
+        track_try_unwrap!(waiting.send_headers(header(REQUEST), true));
+        track_try_unwrap!(run(&mut client));
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        if let Frame::Headers(ref frame) = frames[0] {
+            assert_eq!(frame.stream_id, admitted.id());
+        } else {
+            panic!("{:?}", frames);
+        }
+
+        peer.send(frame::GoawayFrame {
+            last_stream_id: StreamId::new_unchecked(0x7FFF_FFFF),
+            error: ErrorKind::NoError.into(),
+            debug_data: Vec::new(),
+        });
+        let events = track_try_unwrap!(run(&mut client));
+        assert_eq!(events.len(), 1, "{:?}", events);
+        match waiting.poll() {
+            Err(e) => assert_eq!(e.as_code(), 0x7),
+            item => panic!("{:?}", item),
+        }
+        assert!(peer.recv().is_empty());
+    }
+
+    #[test]
+    fn idle_streams_are_reset_without_rst_stream() {
+        let (mut client, peer) = client(&ConnectionBuilder::new());
+        let mut stream = track_try_unwrap!(client.open_stream());
+        track_try_unwrap!(stream.reset(ErrorKind::Cancel));
+        track_try_unwrap!(run(&mut client));
+        assert!(peer.recv().is_empty());
+
+        let mut stream = track_try_unwrap!(client.open_stream());
+        track_try_unwrap!(stream.send_headers(header(REQUEST), true));
+        track_try_unwrap!(run(&mut client));
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        if let Frame::Headers(ref frame) = frames[0] {
+            assert_eq!(frame.stream_id, StreamId::from(3u8));
+        } else {
+            panic!("{:?}", frames);
+        }
+    }
+
+    #[test]
+    fn peer_settings_are_acknowledged_and_applied() {
+        let (mut server, mut peer) = server(&ConnectionBuilder::new());
+        peer.send(SettingsFrame::Syn(vec![Setting::InitialWindowSize(5)]));
+        track_try_unwrap!(run(&mut server));
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        assert!(is_settings(&frames[0], true));
+
+        peer.send_headers(1, REQUEST, true);
+        let mut events = track_try_unwrap!(run(&mut server));
+        let mut stream = match events.pop() {
+            Some(Event::Stream(stream)) => stream,
+            event => panic!("{:?}", event),
+        };
+        track_try_unwrap!(stream.send_headers(header(RESPONSE), false));
+        track_try_unwrap!(stream.send_data(Bytes::new(b"0123456789"), true));
+        track_try_unwrap!(run(&mut server));
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 2, "{:?}", frames);
+        if let Frame::Data(ref frame) = frames[1] {
+            assert_eq!(frame.data, b"01234");
+            assert!(!frame.end_stream);
+        } else {
+            panic!("{:?}", frames);
+        }
+    }
+
+    #[test]
+    fn unexpected_settings_ack_is_rejected() {
+        let (mut server, peer) = server(&ConnectionBuilder::new());
+        peer.send(SettingsFrame::Ack);
+        let e = run_until_error(&mut server);
+        assert_eq!(e.as_code(), 0x1);
+
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        assert_eq!(goaway_code(&frames[0]), Some(0x1));
+    }
+
+    #[test]
+    fn unacknowledged_settings_time_out() {
+        let peer = Peer::new();
+        let mut builder = ConnectionBuilder::new();
+        builder.settings_timeout(Some(Duration::from_millis(1)));
+        let future = builder.connect(peer.input.clone(), peer.output.clone());
+        let mut client = track_try_unwrap!(future.wait());
+        peer.send(SettingsFrame::Syn(Vec::new()));
+        assert!(track_try_unwrap!(run(&mut client)).is_empty());
+        peer.output.0.borrow_mut().clear();
+
+        thread::sleep(Duration::from_millis(10));
+        let e = run_until_error(&mut client);
+        assert_eq!(e.as_code(), 0x4);
+
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        assert_eq!(goaway_code(&frames[0]), Some(0x4));
+    }
+
+    #[test]
+    fn builder_sets_hpack_table_sizes() {
+        let mut builder = ConnectionBuilder::new();
+        assert!(builder.header_table_size(0x1_0000).is_err());
+        track_try_unwrap!(builder.header_table_size(0xFFFF));
+        builder.encoder_table_size(0);
+
+        let (mut client, peer) = client(&builder);
+        let mut stream = track_try_unwrap!(client.open_stream());
+        track_try_unwrap!(stream.send_headers(header(REQUEST), true));
+        track_try_unwrap!(run(&mut client));
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        if let Frame::Headers(ref frame) = frames[0] {
+            // dynamic table size update to zero
+            assert_eq!(frame.fragment[0], 0x20);
+        } else {
+            panic!("{:?}", frames);
+        }
+    }
+
+    #[test]
+    fn rst_stream_on_unsent_streams_is_rejected() {
+        let (mut client, peer) = client(&ConnectionBuilder::new());
+        let _stream = track_try_unwrap!(client.open_stream());
+        peer.send(frame::RstStreamFrame {
+            stream_id: StreamId::from(1u8),
+            error: ErrorKind::Cancel.into(),
+        });
+        let e = run_until_error(&mut client);
+        assert_eq!(e.as_code(), 0x1);
+
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        assert_eq!(goaway_code(&frames[0]), Some(0x1));
+    }
+
+    #[test]
+    fn stream_errors_reset_only_the_stream() {
+        let (mut server, mut peer) = server(&ConnectionBuilder::new());
+        peer.send_headers(1, REQUEST, true);
+        peer.send(frame::DataFrame {
+            stream_id: StreamId::from(1u8),
+            end_stream: false,
+            padding_len: None,
+            data: b"foo".to_vec(),
+        });
+        peer.send_headers(3, REQUEST, true);
+        let events = track_try_unwrap!(run(&mut server));
+        assert_eq!(events.len(), 2, "{:?}", events);
+
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        if let Frame::RstStream(ref frame) = frames[0] {
+            assert_eq!(frame.stream_id, StreamId::from(1u8));
+            assert_eq!(frame.error.as_code(), 0x5);
+        } else {
+            panic!("{:?}", frames);
+        }
+    }
+
+    #[test]
+    fn stream_errors_on_idle_streams_send_no_rst_stream() {
+        let (mut client, peer) = client(&ConnectionBuilder::new());
+        peer.send(SettingsFrame::Syn(vec![Setting::MaxHeaderListSize(1)]));
+        track_try_unwrap!(run(&mut client));
+        assert!(is_settings(&peer.recv()[0], true));
+
+        // The header can not be sent, so the stream never leaves "idle" state.
+        let mut stream = track_try_unwrap!(client.open_stream());
+        track_try_unwrap!(stream.send_headers(header(REQUEST), true));
+        track_try_unwrap!(run(&mut client));
+        assert!(peer.recv().is_empty());
+        assert!(stream.poll().is_err());
+    }
+
+    #[test]
+    fn self_dependent_priority_on_idle_streams_is_rejected() {
+        let (mut server, peer) = server(&ConnectionBuilder::new());
+        peer.send(frame::PriorityFrame {
+            stream_id: StreamId::from(5u8),
+            priority: Priority {
+                is_exclusive: false,
+                stream_dependency: StreamId::from(5u8),
+                weight: track_try_unwrap!(Weight::new(16)),
+            },
+        });
+        let e = run_until_error(&mut server);
+        assert_eq!(e.as_code(), 0x1);
+
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        assert_eq!(goaway_code(&frames[0]), Some(0x1));
+    }
+
+    /// Makes a server connection which has received a request on the stream 1.
+    fn server_with_request(builder: &ConnectionBuilder) -> (Connection<Pipe, Pipe>, Peer, Stream) {
+        let (mut server, mut peer) = server(builder);
+        peer.send_headers(1, REQUEST, true);
+        let mut events = track_try_unwrap!(run(&mut server));
+        let stream = match events.pop() {
+            Some(Event::Stream(stream)) => stream,
+            event => panic!("{:?}", event),
+        };
+        (server, peer, stream)
+    }
+
+    fn promise_stream_id(frame: &Frame<Vec<u8>>) -> Option<(u32, u32)> {
+        if let Frame::PushPromise(ref frame) = *frame {
+            Some((frame.stream_id.as_u32(), frame.promise_stream_id.as_u32()))
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn pushed_streams_get_the_next_even_stream_ids() {
+        let (mut server, peer, stream) = server_with_request(&ConnectionBuilder::new());
+        let mut pushes = vec![
+            stream.push_promise(header(REQUEST)),
+            stream.push_promise(header(REQUEST)),
+        ];
+        track_try_unwrap!(run(&mut server));
+        let second = track_try_unwrap!(pushes.pop().unwrap().wait());
+        let first = track_try_unwrap!(pushes.pop().unwrap().wait());
+        assert_eq!(first.id(), StreamId::from(2u8));
+        assert_eq!(second.id(), StreamId::from(4u8));
+
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 2, "{:?}", frames);
+        assert_eq!(promise_stream_id(&frames[0]), Some((1, 2)));
+        assert_eq!(promise_stream_id(&frames[1]), Some((1, 4)));
+    }
+
+    #[test]
+    fn push_promise_is_written_before_the_pushed_response() {
+        let (mut server, peer, mut stream) = server_with_request(&ConnectionBuilder::new());
+        let mut pushed = track_try_unwrap!(server.push_promise(stream.id(), header(REQUEST)));
+        track_try_unwrap!(pushed.send_headers(header(RESPONSE), false));
+        track_try_unwrap!(pushed.send_data(Bytes::new(b"foo"), true));
+        track_try_unwrap!(stream.send_headers(header(RESPONSE), true));
+        track_try_unwrap!(run(&mut server));
+
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 4, "{:?}", frames);
+        assert_eq!(promise_stream_id(&frames[0]), Some((1, 2)));
+        let stream_ids = frames[1..]
+            .iter()
+            .map(|f| f.frame_header().stream_id.as_u32())
+            .collect::<Vec<_>>();
+        assert_eq!(stream_ids.iter().filter(|&&id| id == 2).count(), 2);
+        assert_eq!(stream_ids.iter().filter(|&&id| id == 1).count(), 1);
+    }
+
+    #[test]
+    fn push_promise_fails_if_the_peer_disables_push() {
+        let (mut server, mut peer, stream) = server_with_request(&ConnectionBuilder::new());
+        peer.send(SettingsFrame::Syn(vec![Setting::EnablePush(false)]));
+        track_try_unwrap!(run(&mut server));
+        assert!(is_settings(&peer.recv()[0], true));
+
+        let e = server.push_promise(stream.id(), header(REQUEST)).err().unwrap();
+        match e.kind() {
+            &ErrorKind::RefusedStream => {}
+            kind => panic!("{:?}", kind),
+        }
+        track_try_unwrap!(run(&mut server));
+        assert!(peer.recv().is_empty());
+    }
+
+    #[test]
+    fn push_promise_respects_peer_max_concurrent_streams() {
+        let (mut server, mut peer, stream) = server_with_request(&ConnectionBuilder::new());
+        peer.send(SettingsFrame::Syn(vec![Setting::MaxConcurrentStreams(1)]));
+        track_try_unwrap!(run(&mut server));
+        assert!(is_settings(&peer.recv()[0], true));
+
+        let mut pushed = track_try_unwrap!(server.push_promise(stream.id(), header(REQUEST)));
+        let e = server.push_promise(stream.id(), header(REQUEST)).err().unwrap();
+        match e.kind() {
+            &ErrorKind::RefusedStream => {}
+            kind => panic!("{:?}", kind),
+        }
+
+        // Another push is allowed once the pushed stream is closed.
+        track_try_unwrap!(pushed.send_headers(header(RESPONSE), true));
+        track_try_unwrap!(run(&mut server));
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 2, "{:?}", frames);
+        let pushed = track_try_unwrap!(server.push_promise(stream.id(), header(REQUEST)));
+        assert_eq!(pushed.id(), StreamId::from(4u8));
+    }
+
+    #[test]
+    fn pushed_streams_are_delivered() {
+        let (mut client, mut peer, _stream) = client_with_request(&ConnectionBuilder::new());
+        peer.send_push_promise(1, 2);
+        let mut events = track_try_unwrap!(run(&mut client));
+        let pushed = match events.pop() {
+            Some(Event::Pushed {
+                parent,
+                promised_request,
+                stream,
+            }) => {
+                assert_eq!(parent, StreamId::from(1u8));
+                assert_eq!(promised_request.get(b":path"), Some(&b"/"[..]));
+                stream
+            }
+            event => panic!("{:?}", event),
+        };
+        assert_eq!(pushed.id(), StreamId::from(2u8));
+
+        peer.send_headers(2, RESPONSE, true);
+        track_try_unwrap!(run(&mut client));
+        assert!(peer.recv().is_empty());
+        let mut items = FuturesStream::wait(pushed);
+        match items.next() {
+            Some(Ok(StreamItem::Header(h))) => assert_eq!(h.get(b":status"), Some(&b"200"[..])),
+            item => panic!("{:?}", item),
+        }
+        assert!(items.next().is_none());
+    }
+
+    #[test]
+    fn pushes_refused_by_the_policy_are_cancelled() {
+        let mut builder = ConnectionBuilder::new();
+        builder.push_policy(RefuseAllPushes);
+        let (mut client, mut peer, _stream) = client_with_request(&builder);
+        peer.send_push_promise(1, 2);
+        assert!(track_try_unwrap!(run(&mut client)).is_empty());
+
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        if let Frame::RstStream(ref frame) = frames[0] {
+            assert_eq!(frame.stream_id, StreamId::from(2u8));
+            assert_eq!(frame.error.as_code(), 0x8);
+        } else {
+            panic!("{:?}", frames);
+        }
+    }
+
+    #[test]
+    fn push_promise_is_rejected_if_push_is_disabled() {
+        let mut builder = ConnectionBuilder::new();
+        builder.enable_push(false);
+        let (mut client, mut peer, _stream) = client_with_request(&builder);
+        peer.send_push_promise(1, 2);
+        let e = run_until_error(&mut client);
+        assert_eq!(e.as_code(), 0x1);
+
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        assert_eq!(goaway_code(&frames[0]), Some(0x1));
+    }
+
+    #[test]
+    fn streams_beyond_max_concurrent_streams_are_refused() {
+        let mut builder = ConnectionBuilder::new();
+        builder.max_concurrent_streams(Some(1));
+        let (mut server, mut peer) = server(&builder);
+        peer.send_headers(1, REQUEST, false);
+        peer.send_headers(3, REQUEST, false);
+        let events = track_try_unwrap!(run(&mut server));
+        assert_eq!(events.len(), 1, "{:?}", events);
+
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        if let Frame::RstStream(ref frame) = frames[0] {
+            assert_eq!(frame.stream_id, StreamId::from(3u8));
+            assert_eq!(frame.error.as_code(), 0x7);
+        } else {
+            panic!("{:?}", frames);
+        }
+    }
+
+    #[test]
+    fn waiting_streams_are_admitted_when_streams_are_closed() {
+        let (mut client, mut peer) = client(&ConnectionBuilder::new());
+        peer.send(SettingsFrame::Syn(vec![Setting::MaxConcurrentStreams(1)]));
+        track_try_unwrap!(run(&mut client));
+        assert!(is_settings(&peer.recv()[0], true));
+
+        let mut first = track_try_unwrap!(client.open_stream());
+        let mut second = track_try_unwrap!(client.open_stream());
+        track_try_unwrap!(first.send_headers(header(REQUEST), true));
+        track_try_unwrap!(second.send_headers(header(REQUEST), true));
+        track_try_unwrap!(run(&mut client));
+        assert_eq!(peer.recv().len(), 1);
+
+        peer.send_headers(1, RESPONSE, true);
+        track_try_unwrap!(run(&mut client));
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        if let Frame::Headers(ref frame) = frames[0] {
+            assert_eq!(frame.stream_id, second.id());
+        } else {
+            panic!("{:?}", frames);
+        }
+    }
+
+    #[test]
+    fn stream_sink_is_ready_while_the_send_window_allows() {
+        let (mut client, mut peer) = client(&ConnectionBuilder::new());
+        peer.send(SettingsFrame::Syn(vec![Setting::InitialWindowSize(10)]));
+        track_try_unwrap!(run(&mut client));
+        assert!(is_settings(&peer.recv()[0], true));
+
+        let mut stream = track_try_unwrap!(client.open_stream());
+        let item = StreamItem::Header(header(REQUEST));
+        assert!(track_try_unwrap!(stream.start_send(item)).is_ready());
+        let item = StreamItem::Data(Bytes::new(b"0123456789"));
+        assert!(track_try_unwrap!(stream.start_send(item)).is_ready());
+        let item = StreamItem::Data(Bytes::new(b"abc"));
+        assert!(track_try_unwrap!(stream.start_send(item)).is_not_ready());
+
+        // The queued data is sent, but the send window is exhausted.
+        track_try_unwrap!(run(&mut client));
+        assert_eq!(peer.recv().len(), 2);
+        let item = StreamItem::Data(Bytes::new(b"abc"));
+        assert!(track_try_unwrap!(stream.start_send(item)).is_not_ready());
+
+        peer.send(frame::WindowUpdateFrame {
+            stream_id: StreamId::from(1u8),
+            window_size_increment: 3,
+        });
+        track_try_unwrap!(run(&mut client));
+        let item = StreamItem::Data(Bytes::new(b"abc"));
+        assert!(track_try_unwrap!(stream.start_send(item)).is_ready());
+        assert!(track_try_unwrap!(stream.close()).is_ready());
+
+        track_try_unwrap!(run(&mut client));
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 2, "{:?}", frames);
+        if let (&Frame::Data(ref data), &Frame::Data(ref end)) = (&frames[0], &frames[1]) {
+            assert_eq!(data.data, b"abc");
+            assert!(end.data.is_empty());
+            assert!(end.end_stream);
+        } else {
+            panic!("{:?}", frames);
+        }
+    }
+
+    #[test]
+    fn send_data_fails_without_resetting_the_stream_while_the_send_queue_is_full() {
+        let (mut client, mut peer) = client(&ConnectionBuilder::new());
+        peer.send(SettingsFrame::Syn(vec![Setting::InitialWindowSize(10)]));
+        track_try_unwrap!(run(&mut client));
+        assert!(is_settings(&peer.recv()[0], true));
+
+        let mut stream = track_try_unwrap!(client.open_stream());
+        track_try_unwrap!(stream.send_headers(header(REQUEST), false));
+        track_try_unwrap!(stream.send_data(Bytes::new(vec![0; 20]), false));
+        let e = stream.send_data(Bytes::new(b"abc"), false).err().unwrap();
+        match e.kind() {
+            &ErrorKind::InternalError => {}
+            kind => panic!("{:?}", kind),
+        }
+        track_try_unwrap!(run(&mut client));
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 2, "{:?}", frames);
+        assert_eq!(frames[1].payload_len(), 10);
+
+        // The rest of the queued data is sent and the stream accepts data again.
+        peer.send(frame::WindowUpdateFrame {
+            stream_id: StreamId::from(1u8),
+            window_size_increment: 20,
+        });
+        track_try_unwrap!(run(&mut client));
+        assert_eq!(peer.recv()[0].payload_len(), 10);
+        track_try_unwrap!(stream.send_data(Bytes::new(b"abc"), true));
+        track_try_unwrap!(run(&mut client));
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        if let Frame::Data(ref frame) = frames[0] {
+            assert_eq!(frame.data, b"abc");
+            assert!(frame.end_stream);
+        } else {
+            panic!("{:?}", frames);
+        }
+    }
+
+    #[test]
+    fn sending_on_streams_of_dropped_connections_fails() {
+        let (mut client, _peer) = client(&ConnectionBuilder::new());
+        let mut stream = track_try_unwrap!(client.open_stream());
+        drop(client);
+
+        let e = stream.send_headers(header(REQUEST), true).err().unwrap();
+        match e.kind() {
+            &ErrorKind::Cancel => {}
+            kind => panic!("{:?}", kind),
+        }
+    }
+
+    #[derive(Debug, Default, Clone)]
+    struct RecordingHandler(Arc<Mutex<Vec<ExtensionFrame<Vec<u8>>>>>);
+    impl ExtensionHandler for RecordingHandler {
+        fn handle_frame(&mut self, frame: ExtensionFrame<Vec<u8>>) -> Result<()> {
+            self.0.lock().unwrap().push(frame);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn extension_frames_are_dispatched_to_registered_handlers() {
+        let (mut server, peer) = server(&ConnectionBuilder::new());
+        let handler = RecordingHandler::default();
+        track_try_unwrap!(server.register_extension_handler(0xFA, handler.clone()));
+        peer.send(ExtensionFrame {
+            frame_type: 0xFA,
+            flags: 0x1,
+            stream_id: StreamId::from(1u8),
+            payload: vec![1, 2, 3],
+        });
+        assert!(track_try_unwrap!(run(&mut server)).is_empty());
+        assert!(peer.recv().is_empty());
+        assert_eq!(server.unknown_frames(), 0);
+
+        let frames = handler.0.lock().unwrap();
+        assert_eq!(frames.len(), 1, "{:?}", *frames);
+        assert_eq!(frames[0].frame_type, 0xFA);
+        assert_eq!(frames[0].flags, 0x1);
+        assert_eq!(frames[0].stream_id, StreamId::from(1u8));
+        assert_eq!(frames[0].payload, [1, 2, 3]);
+    }
+
+    #[test]
+    fn extension_frames_without_handlers_are_discarded() {
+        let (mut server, peer) = server(&ConnectionBuilder::new());
+        let handler = RecordingHandler::default();
+        track_try_unwrap!(server.register_extension_handler(0xFA, handler.clone()));
+        peer.send(ExtensionFrame {
+            frame_type: 0xFB,
+            flags: 0,
+            stream_id: StreamId::from(0u8),
+            payload: vec![1, 2, 3],
+        });
+        peer.send(frame::PingFrame {
+            ack: false,
+            data: [0; 8],
+        });
+        assert!(track_try_unwrap!(run(&mut server)).is_empty());
+        assert!(handler.0.lock().unwrap().is_empty());
+        assert_eq!(server.unknown_frames(), 1);
+
+        // The connection is kept alive.
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        assert_eq!(ping_data(&frames[0]), Some([0; 8]));
+    }
+
+    #[test]
+    fn extension_frames_are_sent() {
+        let (mut server, peer) = server(&ConnectionBuilder::new());
+        track_try_unwrap!(server.send_extension_frame(ExtensionFrame {
+            frame_type: 0xFA,
+            flags: 0x5,
+            stream_id: StreamId::from(3u8),
+            payload: Bytes::new(b"foo"),
+        }));
+        track_try_unwrap!(run(&mut server));
+
+        let frames = peer.recv();
+        assert_eq!(frames.len(), 1, "{:?}", frames);
+        if let Frame::Extension(ref frame) = frames[0] {
+            assert_eq!(frame.frame_type, 0xFA);
+            assert_eq!(frame.flags, 0x5);
+            assert_eq!(frame.stream_id, StreamId::from(3u8));
+            assert_eq!(frame.payload, b"foo");
+        } else {
+            panic!("{:?}", frames);
+        }
+
+        // Frame types defined in RFC 7540 can not be sent as extension frames.
+        let result = server.send_extension_frame(ExtensionFrame {
+            frame_type: 0x6,
+            flags: 0,
+            stream_id: StreamId::from(0u8),
+            payload: Bytes::new(b"12345678"),
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn header_blocks_follow_hpack_table_size_changes() {
+        let (mut client, peer) = client(&ConnectionBuilder::new());
+        let mut decoder = HpackDecoder::new(4096);
+        let mut send_request = |client: &mut Connection<Pipe, Pipe>| {
+            let mut stream = track_try_unwrap!(client.open_stream());
+            track_try_unwrap!(stream.send_headers(header(&[(":method", "GET"), ("x-foo", "bar")]), true));
+            track_try_unwrap!(run(client));
+            let frames = peer.recv();
+            assert_eq!(frames.len(), 1, "{:?}", frames);
+            if let Frame::Headers(ref frame) = frames[0] {
+                let decoded = track_try_unwrap!(Header::decode(&mut decoder, &frame.fragment));
+                assert_eq!(decoded.get(b"x-foo"), Some(&b"bar"[..]));
+                frame.fragment.len()
+            } else {
+                panic!("{:?}", frames);
+            }
+        };
+        let first = send_request(&mut client);
+        let second = send_request(&mut client);
+        assert!(second < first);
+
+        peer.send(SettingsFrame::Syn(vec![Setting::HeaderTableSize(0)]));
+        track_try_unwrap!(run(&mut client));
+        assert!(is_settings(&peer.recv()[0], true));
+        send_request(&mut client);
+    }
+}
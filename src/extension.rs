@@ -0,0 +1,16 @@
+// https://tools.ietf.org/html/rfc7540#section-5.5
+use std::fmt;
+
+use Result;
+use frame::ExtensionFrame;
+
+/// Handler of extension frames (i.e., frames of the types which are not defined in RFC 7540).
+///
+/// Handlers are registered for specific frame types by `Connection::register_extension_handler`.
+/// Extension frames of the types which have no handlers are discarded.
+pub trait ExtensionHandler: fmt::Debug + Send {
+    /// Handles a received extension frame.
+    ///
+    /// If this returns an error, the connection is terminated with the error.
+    fn handle_frame(&mut self, frame: ExtensionFrame<Vec<u8>>) -> Result<()>;
+}
@@ -0,0 +1,126 @@
+// https://tools.ietf.org/html/rfc7540#section-5.2
+use std::fmt;
+
+use {Result, ErrorKind};
+use setting::MAX_FLOW_CONTROL_WINDOW_SIZE;
+
+/// Flow-control window of a stream or a connection.
+///
+/// The size of a window can become negative
+/// if the peer reduces `SETTINGS_INITIAL_WINDOW_SIZE` (RFC 7540#section-6.9.2).
+#[derive(Debug, Clone)]
+pub struct Window {
+    size: i64,
+}
+impl Window {
+    pub fn new(size: u32) -> Self {
+        Window { size: i64::from(size) }
+    }
+    pub fn size(&self) -> i64 {
+        self.size
+    }
+    pub fn available(&self) -> u32 {
+        if self.size < 0 { 0 } else { self.size as u32 }
+    }
+
+    /// Consumes `amount` octets from this window.
+    ///
+    /// If `amount` exceeds the available size, a `FlowControlError` will be returned.
+    pub fn consume(&mut self, amount: u32) -> Result<()> {
+        track_assert!(
+            amount <= self.available(),
+            ErrorKind::FlowControlError,
+            "window={}, amount={}",
+            self.size,
+            amount
+        );
+        self.size -= i64::from(amount);
+        Ok(())
+    }
+
+    /// Increases the size of this window by `increment` octets.
+    ///
+    /// > A sender MUST NOT allow a flow-control window to exceed 2^31-1
+    /// > octets.  If a sender receives a WINDOW_UPDATE that causes a flow-
+    /// > control window to exceed this maximum, it MUST terminate either the
+    /// > stream or the connection, as appropriate.
+    /// >
+    /// > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.9.1)
+    pub fn increase(&mut self, increment: u32) -> Result<()> {
+        let size = self.size + i64::from(increment);
+        track_assert!(
+            size <= i64::from(MAX_FLOW_CONTROL_WINDOW_SIZE),
+            ErrorKind::FlowControlError,
+            "window={}, increment={}",
+            self.size,
+            increment
+        );
+        self.size = size;
+        Ok(())
+    }
+
+    /// Adjusts the size of this window by the difference between
+    /// the old and the new value of `SETTINGS_INITIAL_WINDOW_SIZE`.
+    pub fn update_initial_window_size(&mut self, old: u32, new: u32) -> Result<()> {
+        let size = self.size + i64::from(new) - i64::from(old);
+        track_assert!(
+            size <= i64::from(MAX_FLOW_CONTROL_WINDOW_SIZE),
+            ErrorKind::FlowControlError,
+            "window={}, old_initial={}, new_initial={}",
+            self.size,
+            old,
+            new
+        );
+        self.size = size;
+        Ok(())
+    }
+}
+
+/// Policy which decides when consumed receive windows are replenished by WINDOW_UPDATE frames.
+pub trait ReplenishPolicy: fmt::Debug + Send + Sync {
+    /// Returns `true` if a WINDOW_UPDATE frame should be sent for the window.
+    ///
+    /// `consumed` is the number of octets read by the application since the last replenishment and
+    /// `window_size` is the size of the window when it is fully replenished.
+    fn should_replenish(&self, consumed: u32, window_size: u32) -> bool;
+}
+
+/// Replenishes a window once half of it has been consumed.
+///
+/// This is the default policy.
+#[derive(Debug, Default, Clone)]
+pub struct ReplenishOnHalfWindow;
+impl ReplenishPolicy for ReplenishOnHalfWindow {
+    fn should_replenish(&self, consumed: u32, window_size: u32) -> bool {
+        consumed >= window_size / 2
+    }
+}
+
+/// Replenishes a window every time a DATA frame is consumed.
+#[derive(Debug, Default, Clone)]
+pub struct ReplenishOnEveryFrame;
+impl ReplenishPolicy for ReplenishOnEveryFrame {
+    fn should_replenish(&self, consumed: u32, _window_size: u32) -> bool {
+        consumed > 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn window_works() {
+        let mut window = Window::new(10);
+        assert!(window.consume(11).is_err());
+        assert!(window.consume(10).is_ok());
+        assert_eq!(window.available(), 0);
+
+        assert!(window.update_initial_window_size(10, 5).is_ok());
+        assert_eq!(window.size(), -5);
+        assert_eq!(window.available(), 0);
+
+        assert!(window.increase(MAX_FLOW_CONTROL_WINDOW_SIZE).is_ok());
+        assert!(window.increase(6).is_err());
+    }
+}
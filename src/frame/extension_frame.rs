@@ -0,0 +1,87 @@
+use std::io::{Read, Write};
+use futures::{Future, Poll};
+use handy_async::io::{AsyncRead, AsyncWrite};
+use handy_async::io::futures::{ReadExact, WriteAll};
+
+use Error;
+use stream::StreamId;
+use super::FrameHeader;
+
+/// https://tools.ietf.org/html/rfc7540#section-5.5
+///
+/// A frame of a type which is not defined in RFC 7540 (e.g., ALTSVC and ORIGIN).
+///
+/// The payload is opaque to this crate.
+#[derive(Debug)]
+pub struct ExtensionFrame<B> {
+    pub frame_type: u8,
+    pub flags: u8,
+    pub stream_id: StreamId,
+    pub payload: B,
+}
+impl<B: AsRef<[u8]>> ExtensionFrame<B> {
+    pub fn payload_len(&self) -> usize {
+        self.payload.as_ref().len()
+    }
+    pub fn frame_header(&self) -> FrameHeader {
+        FrameHeader {
+            payload_length: self.payload_len() as u32,
+            frame_type: self.frame_type,
+            flags: self.flags,
+            stream_id: self.stream_id,
+        }
+    }
+    pub fn write_into<W: Write>(self, writer: W) -> WriteExtensionFrame<W, B> {
+        WriteExtensionFrame(writer.async_write_all(self.payload))
+    }
+}
+impl ExtensionFrame<Vec<u8>> {
+    pub fn read_from<R: Read>(reader: R, header: FrameHeader) -> ReadExtensionFrame<R> {
+        let payload = vec![0; header.payload_length as usize];
+        ReadExtensionFrame {
+            header,
+            future: reader.async_read_exact(payload),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct WriteExtensionFrame<W, B>(WriteAll<W, B>);
+impl<W: Write, B: AsRef<[u8]>> Future for WriteExtensionFrame<W, B> {
+    type Item = W;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        Ok(track_async_io!(self.0.poll())?.map(|(writer, _)| writer))
+    }
+}
+
+#[derive(Debug)]
+pub struct ReadExtensionFrame<R> {
+    header: FrameHeader,
+    future: ReadExact<R, Vec<u8>>,
+}
+impl<R> ReadExtensionFrame<R> {
+    pub fn reader(&self) -> &R {
+        self.future.reader()
+    }
+    pub fn reader_mut(&mut self) -> &mut R {
+        self.future.reader_mut()
+    }
+}
+impl<R: Read> Future for ReadExtensionFrame<R> {
+    type Item = (R, ExtensionFrame<Vec<u8>>);
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        Ok(track_async_io!(self.future.poll())?.map(
+            |(reader, payload)| {
+                let frame = ExtensionFrame {
+                    frame_type: self.header.frame_type,
+                    flags: self.header.flags,
+                    stream_id: self.header.stream_id,
+                    payload,
+                };
+                (reader, frame)
+            },
+        ))
+    }
+}
@@ -4,6 +4,7 @@ use handy_async::future::Phase;
 
 pub use self::continuation_frame::ContinuationFrame;
 pub use self::data_frame::DataFrame;
+pub use self::extension_frame::ExtensionFrame;
 pub use self::goaway_frame::GoawayFrame;
 pub use self::headers_frame::HeadersFrame;
 pub use self::ping_frame::PingFrame;
@@ -18,6 +19,7 @@ pub use self::window_update_frame::WindowUpdateFrame;
 use {Error, ErrorKind};
 use self::continuation_frame::{ReadContinuationFrame, WriteContinuationFrame};
 use self::data_frame::{ReadDataFrame, WriteDataFrame};
+use self::extension_frame::{ReadExtensionFrame, WriteExtensionFrame};
 use self::frame_header::{FrameHeader, ReadFrameHeader, WriteFrameHeader};
 use self::goaway_frame::{ReadGoawayFrame, WriteGoawayFrame};
 use self::headers_frame::{ReadHeadersFrame, WriteHeadersFrame};
@@ -30,6 +32,7 @@ use self::window_update_frame::{ReadWindowUpdateFrame, WriteWindowUpdateFrame};
 
 mod continuation_frame;
 mod data_frame;
+mod extension_frame;
 mod frame_header;
 mod goaway_frame;
 mod headers_frame;
@@ -53,10 +56,16 @@ const FRAME_TYPE_GOAWAY: u8 = 0x7;
 const FRAME_TYPE_WINDOW_UPDATE: u8 = 0x8;
 const FRAME_TYPE_CONTINUATION: u8 = 0x9;
 
+/// Returns `true` if `frame_type` is not one of the frame types defined in RFC 7540.
+pub fn is_extension_frame_type(frame_type: u8) -> bool {
+    frame_type > FRAME_TYPE_CONTINUATION
+}
+
 #[derive(Debug)]
 pub enum Frame<B> {
     Continuation(ContinuationFrame<B>),
     Data(DataFrame<B>),
+    Extension(ExtensionFrame<B>),
     Goaway(GoawayFrame),
     Headers(HeadersFrame<B>),
     Ping(PingFrame),
@@ -71,6 +80,7 @@ impl<B: AsRef<[u8]>> Frame<B> {
         match *self {
             Frame::Continuation(ref frame) => frame.payload_len(),
             Frame::Data(ref frame) => frame.payload_len(),
+            Frame::Extension(ref frame) => frame.payload_len(),
             Frame::Goaway(ref frame) => frame.payload_len(),
             Frame::Headers(ref frame) => frame.payload_len(),
             Frame::Ping(ref frame) => frame.payload_len(),
@@ -85,6 +95,7 @@ impl<B: AsRef<[u8]>> Frame<B> {
         match *self {
             Frame::Continuation(ref frame) => frame.frame_header(),
             Frame::Data(ref frame) => frame.frame_header(),
+            Frame::Extension(ref frame) => frame.frame_header(),
             Frame::Goaway(ref frame) => frame.frame_header(),
             Frame::Headers(ref frame) => frame.frame_header(),
             Frame::Ping(ref frame) => frame.frame_header(),
@@ -120,6 +131,11 @@ impl<B> From<DataFrame<B>> for Frame<B> {
         Frame::Data(f)
     }
 }
+impl<B> From<ExtensionFrame<B>> for Frame<B> {
+    fn from(f: ExtensionFrame<B>) -> Self {
+        Frame::Extension(f)
+    }
+}
 impl<B> From<GoawayFrame> for Frame<B> {
     fn from(f: GoawayFrame) -> Self {
         Frame::Goaway(f)
@@ -179,6 +195,9 @@ impl<W: Write, B: AsRef<[u8]>> Future for WriteFrame<W, B> {
                             WriteFramePayload::Continuation(frame.write_into(writer))
                         }
                         Frame::Data(frame) => WriteFramePayload::Data(frame.write_into(writer)),
+                        Frame::Extension(frame) => WriteFramePayload::Extension(
+                            frame.write_into(writer),
+                        ),
                         Frame::Goaway(frame) => WriteFramePayload::Goaway(frame.write_into(writer)),
                         Frame::Headers(frame) => WriteFramePayload::Headers(
                             frame.write_into(writer),
@@ -215,6 +234,7 @@ impl<W: Write, B: AsRef<[u8]>> Future for WriteFrame<W, B> {
 enum WriteFramePayload<W: Write, B: AsRef<[u8]>> {
     Continuation(WriteContinuationFrame<W, B>),
     Data(WriteDataFrame<W, B>),
+    Extension(WriteExtensionFrame<W, B>),
     Goaway(WriteGoawayFrame<W>),
     Headers(WriteHeadersFrame<W, B>),
     Ping(WritePingFrame<W>),
@@ -231,6 +251,7 @@ impl<W: Write, B: AsRef<[u8]>> Future for WriteFramePayload<W, B> {
         match *self {
             WriteFramePayload::Continuation(ref mut f) => track!(f.poll()),
             WriteFramePayload::Data(ref mut f) => track!(f.poll()),
+            WriteFramePayload::Extension(ref mut f) => track!(f.poll()),
             WriteFramePayload::Goaway(ref mut f) => track!(f.poll()),
             WriteFramePayload::Headers(ref mut f) => track!(f.poll()),
             WriteFramePayload::Ping(ref mut f) => track!(f.poll()),
@@ -243,6 +264,9 @@ impl<W: Write, B: AsRef<[u8]>> Future for WriteFramePayload<W, B> {
     }
 }
 
+/// A future which reads a frame.
+///
+/// Frames of unknown types are read as `Frame::Extension`.
 #[derive(Debug)]
 pub struct ReadFrame<R> {
     max_frame_size: u32,
@@ -318,12 +342,8 @@ impl<R: Read> Future for ReadFrame<R> {
                             Phase::B(ReadFramePayload::Continuation(future))
                         }
                         _ => {
-                            // Implementations MUST ignore and discard any frame that has
-                            // a type that is unknown.
-                            // (RFC 7540#section-4.1)
-                            unimplemented!(
-                                "Check payload size and ignore this frame if it is valid"
-                            )
+                            let future = ExtensionFrame::read_from(reader, header);
+                            Phase::B(ReadFramePayload::Extension(future))
                         }
                     }
                 }
@@ -340,6 +360,7 @@ impl<R: Read> Future for ReadFrame<R> {
 enum ReadFramePayload<R> {
     Continuation(ReadContinuationFrame<R>),
     Data(ReadDataFrame<R>),
+    Extension(ReadExtensionFrame<R>),
     Goaway(ReadGoawayFrame<R>),
     Headers(ReadHeadersFrame<R>),
     Ping(ReadPingFrame<R>),
@@ -354,6 +375,7 @@ impl<R> ReadFramePayload<R> {
         match *self {
             ReadFramePayload::Continuation(ref f) => f.reader(),
             ReadFramePayload::Data(ref f) => f.reader(),
+            ReadFramePayload::Extension(ref f) => f.reader(),
             ReadFramePayload::Goaway(ref f) => f.reader(),
             ReadFramePayload::Headers(ref f) => f.reader(),
             ReadFramePayload::Ping(ref f) => f.reader(),
@@ -368,6 +390,7 @@ impl<R> ReadFramePayload<R> {
         match *self {
             ReadFramePayload::Continuation(ref mut f) => f.reader_mut(),
             ReadFramePayload::Data(ref mut f) => f.reader_mut(),
+            ReadFramePayload::Extension(ref mut f) => f.reader_mut(),
             ReadFramePayload::Goaway(ref mut f) => f.reader_mut(),
             ReadFramePayload::Headers(ref mut f) => f.reader_mut(),
             ReadFramePayload::Ping(ref mut f) => f.reader_mut(),
@@ -392,6 +415,11 @@ impl<R: Read> Future for ReadFramePayload<R> {
             ReadFramePayload::Data(ref mut f) => Ok(track!(f.poll())?.map(|(reader, frame)| {
                 (reader, Frame::Data(frame))
             })),
+            ReadFramePayload::Extension(ref mut f) => Ok(
+                track!(f.poll())?.map(|(reader, frame)| {
+                    (reader, Frame::Extension(frame))
+                }),
+            ),
             ReadFramePayload::Goaway(ref mut f) => {
                 Ok(track!(f.poll())?.map(|(reader, frame)| {
                     (reader, Frame::Goaway(frame))
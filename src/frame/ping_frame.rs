@@ -33,7 +33,7 @@ impl PingFrame {
         FrameHeader {
             payload_length: self.payload_len() as u32,
             frame_type: super::FRAME_TYPE_PING,
-            flags: 0,
+            flags: if self.ack { FLAG_ACK } else { 0 },
             stream_id: StreamId::connection_control_stream_id(),
         }
     }
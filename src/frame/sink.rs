@@ -7,48 +7,124 @@ use futures::{Sink, StartSend, Poll, Async, AsyncSink, Future};
 use Error;
 use frame::{Frame, WriteFrame};
 
+/// A sink which writes frames into `W`.
+///
+/// Control frames (i.e., non-DATA frames) are written in preference to DATA frames.
 #[derive(Debug)]
 pub struct FrameSink<W: Write, B: AsRef<[u8]>> {
-    queue: VecDeque<Frame<B>>,
+    control_queue: VecDeque<(Frame<B>, bool)>,
+    data_queue: VecDeque<Frame<B>>,
     state: FrameSinkState<W, B>,
+    is_writing_data: bool,
+    is_writing_reply: bool,
+
+    // The number of the reply frames in `control_queue`.
+    queued_replies: usize,
 }
 impl<W: Write, B: AsRef<[u8]>> FrameSink<W, B> {
     pub fn new(writer: W) -> Self {
         FrameSink {
-            queue: VecDeque::new(),
+            control_queue: VecDeque::new(),
+            data_queue: VecDeque::new(),
             state: FrameSinkState::Idle(writer),
+            is_writing_data: false,
+            is_writing_reply: false,
+            queued_replies: 0,
         }
     }
     pub fn start_write_frame<T: Into<Frame<B>>>(&mut self, frame: T) {
         let _ = self.start_send(frame.into());
     }
-}
-impl<W: Write, B: AsRef<[u8]>> Sink for FrameSink<W, B> {
-    type SinkItem = Frame<B>;
-    type SinkError = Error;
-    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+
+    /// Starts writing `frame` which is sent in reply to a frame received from the peer
+    /// (e.g., an acknowledgement of SETTINGS or PING).
+    ///
+    /// The number of such frames can be queried by `reply_len` method.
+    pub fn start_write_reply_frame<T: Into<Frame<B>>>(&mut self, frame: T) {
+        self.push_frame(frame.into(), true);
+    }
+
+    /// Returns the number of frames being written or waiting to be written.
+    pub fn len(&self) -> usize {
+        if self.is_empty() {
+            0
+        } else {
+            self.control_queue.len() + self.data_queue.len() + 1
+        }
+    }
+
+    /// Returns the number of reply frames being written or waiting to be written.
+    ///
+    /// See `start_write_reply_frame` method.
+    pub fn reply_len(&self) -> usize {
+        self.queued_replies + if self.is_writing_reply { 1 } else { 0 }
+    }
+
+    /// Returns `true` if there are DATA frames being written or waiting to be written.
+    pub fn has_pending_data_frames(&self) -> bool {
+        self.is_writing_data || !self.data_queue.is_empty()
+    }
+
+    /// Returns `true` if there are no frames being written or waiting to be written.
+    pub fn is_empty(&self) -> bool {
+        if let FrameSinkState::Writing(_) = self.state {
+            false
+        } else {
+            true
+        }
+    }
+
+    fn push_frame(&mut self, frame: Frame<B>, is_reply: bool) {
         if let FrameSinkState::Writing(_) = self.state {
-            self.queue.push_back(item);
+            if let Frame::Data(_) = frame {
+                self.data_queue.push_back(frame);
+            } else {
+                if is_reply {
+                    self.queued_replies += 1;
+                }
+                self.control_queue.push_back((frame, is_reply));
+            }
         } else if let FrameSinkState::Idle(writer) =
             mem::replace(&mut self.state, FrameSinkState::Done)
         {
-            self.state = FrameSinkState::Writing(item.write_into(writer));
+            self.is_writing_data = if let Frame::Data(_) = frame { true } else { false };
+            self.is_writing_reply = is_reply;
+            self.state = FrameSinkState::Writing(frame.write_into(writer));
         } else {
             unreachable!()
         }
+    }
+}
+impl<W: Write, B: AsRef<[u8]>> Sink for FrameSink<W, B> {
+    type SinkItem = Frame<B>;
+    type SinkError = Error;
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        self.push_frame(item, false);
         Ok(AsyncSink::Ready)
     }
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
         loop {
             let next = if let FrameSinkState::Writing(ref mut f) = self.state {
                 if let Async::Ready(writer) = track!(f.poll())? {
-                    if let Some(frame) = self.queue.pop_front() {
+                    let next = if let Some((frame, is_reply)) = self.control_queue.pop_front() {
+                        if is_reply {
+                            self.queued_replies -= 1;
+                        }
+                        Some((frame, is_reply))
+                    } else {
+                        self.data_queue.pop_front().map(|frame| (frame, false))
+                    };
+                    if let Some((frame, is_reply)) = next {
+                        self.is_writing_data = if let Frame::Data(_) = frame { true } else { false };
+                        self.is_writing_reply = is_reply;
                         FrameSinkState::Writing(frame.write_into(writer))
                     } else {
+                        self.is_writing_data = false;
+                        self.is_writing_reply = false;
                         FrameSinkState::Idle(writer)
                     }
                 } else {
-                    break;
+                    return Ok(Async::NotReady);
                 }
             } else {
                 break;
@@ -0,0 +1,171 @@
+// https://tools.ietf.org/html/rfc7540#section-8.1.2
+use std::str;
+use trackable::error::ErrorKindExt;
+
+use {Result, ErrorKind};
+use header::{Header, HeaderKind};
+
+/// The head of a request (i.e., the pseudo-header fields and the regular header fields).
+#[derive(Debug)]
+pub struct RequestHead {
+    /// The value of `:method` pseudo-header field.
+    pub method: String,
+
+    /// The value of `:scheme` pseudo-header field (omitted in CONNECT requests).
+    pub scheme: Option<String>,
+
+    /// The value of `:authority` pseudo-header field.
+    pub authority: Option<String>,
+
+    /// The value of `:path` pseudo-header field (omitted in CONNECT requests).
+    pub path: Option<String>,
+
+    /// The regular header fields.
+    pub headers: Header,
+}
+impl RequestHead {
+    /// Makes a new `RequestHead` instance which has no authority and no regular header fields.
+    pub fn new(method: &str, scheme: &str, path: &str) -> Self {
+        RequestHead {
+            method: method.to_owned(),
+            scheme: Some(scheme.to_owned()),
+            authority: None,
+            path: Some(path.to_owned()),
+            headers: Header::new(),
+        }
+    }
+
+    /// Parses a request head from `header`.
+    ///
+    /// If `header` is not a valid request header, a `ProtocolError` will be returned.
+    pub fn from_header(header: &Header) -> Result<Self> {
+        track!(header.validate(HeaderKind::Request))?;
+        let mut head = RequestHead {
+            method: String::new(),
+            scheme: None,
+            authority: None,
+            path: None,
+            headers: Header::new(),
+        };
+        for (name, value) in header.fields() {
+            match name {
+                b":method" => head.method = track!(to_string(value))?,
+                b":scheme" => head.scheme = Some(track!(to_string(value))?),
+                b":authority" => head.authority = Some(track!(to_string(value))?),
+                b":path" => head.path = Some(track!(to_string(value))?),
+                _ => head.headers.add_field(name, value),
+            }
+        }
+        Ok(head)
+    }
+
+    /// Converts this into a `Header` instance.
+    pub fn to_header(&self) -> Header {
+        let mut header = Header::new();
+        header.add_field(b":method", self.method.as_bytes());
+        if let Some(ref scheme) = self.scheme {
+            header.add_field(b":scheme", scheme.as_bytes());
+        }
+        if let Some(ref authority) = self.authority {
+            header.add_field(b":authority", authority.as_bytes());
+        }
+        if let Some(ref path) = self.path {
+            header.add_field(b":path", path.as_bytes());
+        }
+        for (name, value) in self.headers.fields() {
+            header.add_field(name, value);
+        }
+        header
+    }
+}
+
+/// The head of a response (i.e., the status code and the regular header fields).
+#[derive(Debug)]
+pub struct ResponseHead {
+    /// The value of `:status` pseudo-header field.
+    pub status: u16,
+
+    /// The regular header fields.
+    pub headers: Header,
+}
+impl ResponseHead {
+    /// Makes a new `ResponseHead` instance which has no regular header fields.
+    pub fn new(status: u16) -> Self {
+        ResponseHead {
+            status,
+            headers: Header::new(),
+        }
+    }
+
+    /// Parses a response head from `header`.
+    ///
+    /// If `header` is not a valid response header, a `ProtocolError` will be returned.
+    pub fn from_header(header: &Header) -> Result<Self> {
+        track!(header.validate(HeaderKind::Response))?;
+        let mut head = ResponseHead::new(0);
+        for (name, value) in header.fields() {
+            if name == b":status" {
+                track_assert_eq!(value.len(), 3, ErrorKind::ProtocolError);
+                head.status = track!(to_string(value)?.parse().map_err(|e| {
+                    ErrorKind::ProtocolError.cause(e)
+                }))?;
+            } else {
+                head.headers.add_field(name, value);
+            }
+        }
+        Ok(head)
+    }
+
+    /// Converts this into a `Header` instance.
+    pub fn to_header(&self) -> Header {
+        let mut header = Header::new();
+        header.add_field(b":status", self.status.to_string().as_bytes());
+        for (name, value) in self.headers.fields() {
+            header.add_field(name, value);
+        }
+        header
+    }
+}
+
+fn to_string(bytes: &[u8]) -> Result<String> {
+    let s = track!(str::from_utf8(bytes).map_err(|e| ErrorKind::ProtocolError.cause(e)))?;
+    Ok(s.to_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn request_head_works() {
+        let mut head = RequestHead::new("GET", "https", "/index.html");
+        head.authority = Some("example.com".to_owned());
+        head.headers.add_field(b"accept", b"text/html");
+
+        let header = head.to_header();
+        let head = track_try_unwrap!(RequestHead::from_header(&header));
+        assert_eq!(head.method, "GET");
+        assert_eq!(head.scheme.as_ref().map(String::as_str), Some("https"));
+        assert_eq!(head.authority.as_ref().map(String::as_str), Some("example.com"));
+        assert_eq!(head.path.as_ref().map(String::as_str), Some("/index.html"));
+        assert_eq!(head.headers.get(b"accept"), Some(&b"text/html"[..]));
+        assert_eq!(head.headers.fields().count(), 1);
+    }
+
+    #[test]
+    fn response_head_works() {
+        let mut head = ResponseHead::new(404);
+        head.headers.add_field(b"content-length", b"0");
+
+        let header = head.to_header();
+        assert_eq!(header.get(b":status"), Some(&b"404"[..]));
+
+        let head = track_try_unwrap!(ResponseHead::from_header(&header));
+        assert_eq!(head.status, 404);
+        assert_eq!(head.headers.get(b"content-length"), Some(&b"0"[..]));
+
+        let mut header = Header::new();
+        header.add_field(b":status", b"2xx");
+        assert!(ResponseHead::from_header(&header).is_err());
+    }
+}
@@ -1,31 +1,285 @@
 use std::fmt;
 
-use hpack_codec::Decoder as HpackDecoder;
+use hpack_codec::{Decoder as HpackDecoder, Encoder as HpackEncoder};
+use hpack_codec::field::LiteralHeaderField;
+use hpack_codec::table::{Index, Table};
 
-use Result;
+use {Result, ErrorKind};
+
+/// The kind of a header block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderKind {
+    Request,
+    Response,
+    Trailers,
+}
 
 pub struct Header {
     fields: Vec<FieldPosition>,
     buf: Vec<u8>,
 }
 impl Header {
+    /// Makes a new empty `Header` instance.
+    pub fn new() -> Self {
+        Header {
+            fields: Vec::new(),
+            buf: Vec::new(),
+        }
+    }
+
+    /// Appends a header field.
+    pub fn add_field(&mut self, name: &[u8], value: &[u8]) {
+        let name_offset = self.buf.len();
+        self.buf.extend_from_slice(name);
+
+        let value_offset = self.buf.len();
+        self.buf.extend_from_slice(value);
+
+        self.fields.push(FieldPosition {
+            name_offset,
+            value_offset,
+        });
+    }
+
+    /// Encodes this header into a header block.
+    ///
+    /// The fields found in the HPACK table are represented by their indices,
+    /// and the other fields are added to the dynamic table if they fit in it.
+    pub fn encode(&self, encoder: &mut HpackEncoder) -> Result<Vec<u8>> {
+        let mut block = track!(encoder.enter_header_block(Vec::new()))?;
+        for (name, value) in self.fields() {
+            let (field_index, name_index) = find_field(block.table(), name, value);
+            if let Some(index) = field_index {
+                track!(block.encode_field(index))?;
+                continue;
+            }
+
+            let field = if let Some(index) = name_index {
+                LiteralHeaderField::with_indexed_name(index, value)
+            } else {
+                LiteralHeaderField::new(name, value)
+            };
+            let table_size = block.table().dynamic().size_soft_limit();
+            if field_size(name, value) <= table_size as usize {
+                track!(block.encode_field(field.with_indexing()))?;
+            } else {
+                // Adding an entry larger than the table would just empty the table.
+                track!(block.encode_field(field))?;
+            }
+        }
+        Ok(block.finish())
+    }
+    /// Validates this header as a header block of `kind` in accordance with
+    /// [RFC 7540#section-8.1.2](https://tools.ietf.org/html/rfc7540#section-8.1.2).
+    ///
+    /// If this header is malformed, a `ProtocolError` will be returned.
+    ///
+    /// > A request or response that includes a payload body can include a
+    /// > content-length header field.  A request or response is also
+    /// > malformed if the value of a content-length header field does not
+    /// > equal the sum of the DATA frame payload lengths that form the body.
+    /// >
+    /// > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-8.1.2.6)
+    ///
+    /// Note that the above `content-length` rule is not checked by this method.
+    pub fn validate(&self, kind: HeaderKind) -> Result<()> {
+        let mut pseudo_headers: Vec<&[u8]> = Vec::new();
+        let mut is_regular_field_seen = false;
+        for (name, value) in self.fields() {
+            // > Just as in HTTP/1.x, header field names are strings of ASCII
+            // > characters that are compared in a case-insensitive fashion.
+            // > However, header field names MUST be converted to lowercase prior to
+            // > their encoding in HTTP/2.  A request or response containing
+            // > uppercase header field names MUST be treated as malformed
+            // > (Section 8.1.2.6).
+            // >
+            // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-8.1.2)
+            track_assert!(
+                !name.iter().any(|b| b.is_ascii_uppercase()),
+                ErrorKind::ProtocolError,
+                "Uppercase field name: {:?}",
+                String::from_utf8_lossy(name)
+            );
+
+            if name.first() == Some(&b':') {
+                // > All pseudo-header fields MUST appear in the header block before
+                // > regular header fields.  Any request or response that contains a
+                // > pseudo-header field that appears in a header block after a regular
+                // > header field MUST be treated as malformed (Section 8.1.2.6).
+                // >
+                // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-8.1.2.1)
+                track_assert!(
+                    !is_regular_field_seen,
+                    ErrorKind::ProtocolError,
+                    "Pseudo-header field after regular fields: {:?}",
+                    String::from_utf8_lossy(name)
+                );
+
+                // > Pseudo-header fields are only valid in the context in which they are
+                // > defined.  Pseudo-header fields defined for requests MUST NOT appear
+                // > in responses; pseudo-header fields defined for responses MUST NOT
+                // > appear in requests.  Pseudo-header fields MUST NOT appear in
+                // > trailers.  Endpoints MUST treat a request or response that contains
+                // > undefined or invalid pseudo-header fields as malformed
+                // > (Section 8.1.2.6).
+                // >
+                // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-8.1.2.1)
+                let is_defined = match kind {
+                    HeaderKind::Request => {
+                        [&b":method"[..], b":scheme", b":authority", b":path"].contains(&name)
+                    }
+                    HeaderKind::Response => name == b":status",
+                    HeaderKind::Trailers => false,
+                };
+                track_assert!(
+                    is_defined,
+                    ErrorKind::ProtocolError,
+                    "Undefined pseudo-header field: {:?}",
+                    String::from_utf8_lossy(name)
+                );
+                track_assert!(
+                    !pseudo_headers.contains(&name),
+                    ErrorKind::ProtocolError,
+                    "Duplicate pseudo-header field: {:?}",
+                    String::from_utf8_lossy(name)
+                );
+                pseudo_headers.push(name);
+            } else {
+                is_regular_field_seen = true;
+
+                // > HTTP/2 does not use the Connection header field to indicate
+                // > connection-specific header fields; in this protocol, connection-
+                // > specific metadata is conveyed by other means.  An endpoint MUST NOT
+                // > generate an HTTP/2 message containing connection-specific header
+                // > fields; any message containing connection-specific header fields
+                // > MUST be treated as malformed (Section 8.1.2.6).
+                // >
+                // > The only exception to this is the TE header field, which MAY be
+                // > present in an HTTP/2 request; when it is, it MUST NOT contain any
+                // > value other than "trailers".
+                // >
+                // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-8.1.2.2)
+                let connection_specific_fields = [
+                    &b"connection"[..],
+                    b"keep-alive",
+                    b"proxy-connection",
+                    b"transfer-encoding",
+                    b"upgrade",
+                ];
+                track_assert!(
+                    !connection_specific_fields.contains(&name),
+                    ErrorKind::ProtocolError,
+                    "Connection-specific header field: {:?}",
+                    String::from_utf8_lossy(name)
+                );
+                if name == b"te" {
+                    track_assert_eq!(kind, HeaderKind::Request, ErrorKind::ProtocolError);
+                    track_assert_eq!(value, b"trailers", ErrorKind::ProtocolError);
+                }
+            }
+        }
+
+        let has = |name: &[u8]| pseudo_headers.contains(&name);
+        match kind {
+            HeaderKind::Request => {
+                if self.get(b":method") == Some(b"CONNECT") {
+                    // > The :scheme and :path pseudo-header fields MUST be omitted.
+                    // > The :authority pseudo-header field contains the host and port to
+                    // > connect to (equivalent to the authority-form of the request-target
+                    // > of CONNECT requests (see [RFC7230], Section 5.3)).
+                    // >
+                    // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-8.3)
+                    track_assert!(has(b":authority"), ErrorKind::ProtocolError);
+                    track_assert!(!has(b":scheme"), ErrorKind::ProtocolError);
+                    track_assert!(!has(b":path"), ErrorKind::ProtocolError);
+                } else {
+                    // > All HTTP/2 requests MUST include exactly one valid value for the
+                    // > :method, :scheme, and :path pseudo-header fields, unless it is a
+                    // > CONNECT request (Section 8.3).  An HTTP request that omits
+                    // > mandatory pseudo-header fields is malformed (Section 8.1.2.6).
+                    // >
+                    // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-8.1.2.3)
+                    track_assert!(has(b":method"), ErrorKind::ProtocolError);
+                    track_assert!(has(b":scheme"), ErrorKind::ProtocolError);
+                    track_assert!(has(b":path"), ErrorKind::ProtocolError);
+                    track_assert!(
+                        self.get(b":path") != Some(b""),
+                        ErrorKind::ProtocolError,
+                        "Empty :path"
+                    );
+                }
+            }
+            HeaderKind::Response => {
+                // > For HTTP/2 responses, a single :status pseudo-header field is
+                // > defined that carries the HTTP status code field (see [RFC7231],
+                // > Section 6).  This pseudo-header field MUST be included in all
+                // > responses; otherwise, the response is malformed (Section 8.1.2.6).
+                // >
+                // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-8.1.2.4)
+                track_assert!(has(b":status"), ErrorKind::ProtocolError);
+            }
+            HeaderKind::Trailers => {}
+        }
+        Ok(())
+    }
+
+    /// Returns the value of the first field named `name`.
+    pub fn get(&self, name: &[u8]) -> Option<&[u8]> {
+        self.fields().find(|f| f.0 == name).map(|f| f.1)
+    }
+
+    /// Returns `true` if this is the header of an informational (1xx) response.
+    pub fn is_informational(&self) -> bool {
+        if let Some(status) = self.get(b":status") {
+            status.len() == 3 && status[0] == b'1'
+        } else {
+            false
+        }
+    }
+
+    /// Returns the size of this header list.
+    ///
+    /// > SETTINGS_MAX_HEADER_LIST_SIZE (0x6):  This advisory setting informs a
+    /// > peer of the maximum size of header list that the sender is
+    /// > prepared to accept, in octets.  The value is based on the
+    /// > uncompressed size of header fields, including the length of the
+    /// > name and value in octets plus an overhead of 32 octets for each
+    /// > header field.
+    /// >
+    /// > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-6.5.2)
+    pub fn list_size(&self) -> usize {
+        self.fields().map(|(n, v)| field_size(n, v)).sum()
+    }
     pub fn decode(decoder: &mut HpackDecoder, block: &[u8]) -> Result<Self> {
-        let mut header = track!(decoder.enter_header_block(block))?;
-        let mut fields = Vec::new();
-        let mut buf = Vec::new();
-        while let Some(field) = track!(header.decode_field())? {
-            let name_offset = buf.len();
-            buf.extend_from_slice(field.name());
-
-            let value_offset = buf.len();
-            buf.extend_from_slice(field.value());
-
-            fields.push(FieldPosition {
-                name_offset,
-                value_offset,
-            });
+        let header = track!(Self::decode_with_limit(decoder, block, None))?;
+        Ok(header.expect("Never fails"))
+    }
+
+    /// Decodes a header block whose list size (see `list_size` method) must not exceed `limit`.
+    ///
+    /// If the limit is exceeded, `None` will be returned.
+    /// Note that the whole block is decoded even in that case to keep the HPACK context in sync,
+    /// but the fields beyond the limit are not retained.
+    pub fn decode_with_limit(
+        decoder: &mut HpackDecoder,
+        block: &[u8],
+        limit: Option<u32>,
+    ) -> Result<Option<Self>> {
+        let limit = if let Some(n) = limit { n as usize } else { usize::max_value() };
+        let mut block = track!(decoder.enter_header_block(block))?;
+        let mut header = Header::new();
+        let mut size = 0;
+        while let Some(field) = track!(block.decode_field())? {
+            size += field_size(field.name(), field.value());
+            if size <= limit {
+                header.add_field(field.name(), field.value());
+            }
+        }
+        if size <= limit {
+            Ok(Some(header))
+        } else {
+            Ok(None)
         }
-        Ok(Header { fields, buf })
     }
     pub fn fields(&self) -> Fields {
         Fields {
@@ -47,6 +301,11 @@ impl Header {
         }
     }
 }
+impl Default for Header {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl fmt::Debug for Header {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use std::str;
@@ -72,6 +331,30 @@ impl fmt::Debug for Header {
     }
 }
 
+fn field_size(name: &[u8], value: &[u8]) -> usize {
+    name.len() + value.len() + 32
+}
+
+/// Returns the index of the entry which is equal to the field `name: value`
+/// and the index of the first entry which has the name `name`.
+fn find_field(table: &Table, name: &[u8], value: &[u8]) -> (Option<Index>, Option<Index>) {
+    let mut name_index = None;
+    for i in 1..table.len() + 1 {
+        let index = Index::new(i).expect("Never fails");
+        let entry = table.get(index).expect("Never fails");
+        if entry.name() != name {
+            continue;
+        }
+        if entry.value() == value {
+            return (Some(index), name_index);
+        }
+        if name_index.is_none() {
+            name_index = Some(index);
+        }
+    }
+    (None, name_index)
+}
+
 #[derive(Debug, Clone, Copy)]
 struct FieldPosition {
     name_offset: usize,
@@ -94,3 +377,101 @@ impl<'a> Iterator for Fields<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn header(fields: &[(&str, &str)]) -> Header {
+        let mut header = Header::new();
+        for &(name, value) in fields {
+            header.add_field(name.as_bytes(), value.as_bytes());
+        }
+        header
+    }
+
+    #[test]
+    fn validate_works() {
+        let request = [(":method", "GET"), (":scheme", "https"), (":path", "/")];
+        assert!(header(&request).validate(HeaderKind::Request).is_ok());
+        assert!(header(&request).validate(HeaderKind::Response).is_err());
+        assert!(header(&request).validate(HeaderKind::Trailers).is_err());
+        assert!(header(&request[..2]).validate(HeaderKind::Request).is_err());
+
+        let connect = [(":method", "CONNECT"), (":authority", "example.com:443")];
+        assert!(header(&connect).validate(HeaderKind::Request).is_ok());
+
+        let response = [(":status", "200"), ("content-type", "text/plain")];
+        assert!(header(&response).validate(HeaderKind::Response).is_ok());
+        assert!(header(&[]).validate(HeaderKind::Response).is_err());
+        assert!(header(&[("grpc-status", "0")]).validate(HeaderKind::Trailers).is_ok());
+
+        // malformed
+        let uppercase = [(":status", "200"), ("Content-Type", "text/plain")];
+        assert!(header(&uppercase).validate(HeaderKind::Response).is_err());
+
+        let misordered = [("content-type", "text/plain"), (":status", "200")];
+        assert!(header(&misordered).validate(HeaderKind::Response).is_err());
+
+        let unknown = [(":status", "200"), (":foo", "bar")];
+        assert!(header(&unknown).validate(HeaderKind::Response).is_err());
+
+        let duplicate = [(":status", "200"), (":status", "204")];
+        assert!(header(&duplicate).validate(HeaderKind::Response).is_err());
+
+        let connection = [(":status", "200"), ("connection", "keep-alive")];
+        assert!(header(&connection).validate(HeaderKind::Response).is_err());
+
+        let te = [(":method", "GET"), (":scheme", "https"), (":path", "/"), ("te", "gzip")];
+        assert!(header(&te).validate(HeaderKind::Request).is_err());
+        let te = [(":method", "GET"), (":scheme", "https"), (":path", "/"), ("te", "trailers")];
+        assert!(header(&te).validate(HeaderKind::Request).is_ok());
+        let te = [(":status", "200"), ("te", "trailers")];
+        assert!(header(&te).validate(HeaderKind::Response).is_err());
+        assert!(header(&te[1..]).validate(HeaderKind::Trailers).is_err());
+    }
+
+    #[test]
+    fn decode_with_limit_works() {
+        let header = header(&[(":status", "200"), ("content-type", "text/plain")]);
+        assert_eq!(header.list_size(), (7 + 3 + 32) + (12 + 10 + 32));
+
+        let mut encoder = HpackEncoder::new(4096);
+        let block = track_try_unwrap!(header.encode(&mut encoder));
+
+        let mut decoder = HpackDecoder::new(4096);
+        let limit = Some(header.list_size() as u32);
+        let decoded = track_try_unwrap!(Header::decode_with_limit(&mut decoder, &block, limit));
+        assert!(decoded.is_some());
+
+        let limit = Some(header.list_size() as u32 - 1);
+        let decoded = track_try_unwrap!(Header::decode_with_limit(&mut decoder, &block, limit));
+        assert!(decoded.is_none());
+    }
+
+    #[test]
+    fn encode_and_decode_works() {
+        let mut header = Header::new();
+        header.add_field(b":status", b"200");
+        header.add_field(b"content-type", b"text/plain");
+
+        let mut encoder = HpackEncoder::new(4096);
+        let block = track_try_unwrap!(header.encode(&mut encoder));
+
+        let mut decoder = HpackDecoder::new(4096);
+        let decoded = track_try_unwrap!(Header::decode(&mut decoder, &block));
+        assert_eq!(
+            decoded.fields().collect::<Vec<_>>(),
+            [
+                (&b":status"[..], &b"200"[..]),
+                (&b"content-type"[..], &b"text/plain"[..]),
+            ]
+        );
+
+        // Both fields are in the table now.
+        let block = track_try_unwrap!(header.encode(&mut encoder));
+        assert_eq!(block.len(), 2);
+        let decoded = track_try_unwrap!(Header::decode(&mut decoder, &block));
+        assert_eq!(decoded.list_size(), header.list_size());
+    }
+}
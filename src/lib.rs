@@ -22,10 +22,15 @@ macro_rules! track_async_io {
 }
 
 pub mod connection;
+pub mod extension;
+pub mod flow_control;
 pub mod frame;
+pub mod head;
 pub mod header;
 pub mod preface;
 pub mod priority;
+pub mod priority_tree;
+pub mod push;
 pub mod setting;
 pub mod stream;
 
@@ -96,4 +101,30 @@ mod test {
 
         assert!(input.is_empty());
     }
+
+    #[test]
+    fn unknown_frames_are_read_as_extension_frames() {
+        let data = [
+            0, 0, 3, 0xFA, 0x1, 0, 0, 0, 0, 1, 2, 3, // an unknown frame (type=0xFA)
+            0, 0, 8, 6, 0, 0, 0, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, // PING
+        ];
+        let future = Frame::read_from(&data[..], 0xFFFF);
+        let (input, frame) = track_try_unwrap!(future.wait());
+        if let Frame::Extension(frame) = frame {
+            assert_eq!(frame.frame_type, 0xFA);
+            assert_eq!(frame.flags, 0x1);
+            assert!(frame.stream_id.is_connection_control_stream());
+            assert_eq!(frame.payload, [1, 2, 3]);
+        } else {
+            panic!("{:?}", frame);
+        }
+
+        let (input, frame) = track_try_unwrap!(Frame::read_from(input, 0xFFFF).wait());
+        if let Frame::Ping(frame) = frame {
+            assert_eq!(frame.data, [1, 2, 3, 4, 5, 6, 7, 8]);
+        } else {
+            panic!("{:?}", frame);
+        }
+        assert!(input.is_empty());
+    }
 }
@@ -1,8 +1,8 @@
 // https://tools.ietf.org/html/rfc7540#section-3.5
-use std::io::Read;
+use std::io::{Read, Write};
 use futures::{Future, Poll, Async};
-use handy_async::io::AsyncRead;
-use handy_async::io::futures::ReadExact;
+use handy_async::io::{AsyncRead, AsyncWrite};
+use handy_async::io::futures::{ReadExact, WriteAll};
 
 use {Error, ErrorKind};
 
@@ -12,6 +12,10 @@ pub fn read_preface<R: Read>(reader: R) -> ReadPreface<R> {
     ReadPreface(reader.async_read_exact([0; 24]))
 }
 
+pub fn write_preface<W: Write>(writer: W) -> WritePreface<W> {
+    WritePreface(writer.async_write_all(PREFACE_BYTES))
+}
+
 #[derive(Debug)]
 pub struct ReadPreface<R>(ReadExact<R, [u8; 24]>);
 impl<R: Read> Future for ReadPreface<R> {
@@ -26,3 +30,13 @@ impl<R: Read> Future for ReadPreface<R> {
         }
     }
 }
+
+#[derive(Debug)]
+pub struct WritePreface<W>(WriteAll<W, [u8; 24]>);
+impl<W: Write> Future for WritePreface<W> {
+    type Item = W;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        Ok(track_async_io!(self.0.poll())?.map(|(writer, _)| writer))
+    }
+}
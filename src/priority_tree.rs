@@ -0,0 +1,522 @@
+// https://tools.ietf.org/html/rfc7540#section-5.3
+use std::cmp;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::mem;
+
+use {Result, ErrorKind};
+use priority::{Priority, Weight};
+use stream::StreamId;
+
+/// The default number of inactive (idle or closed) streams retained in a `PriorityTree`.
+pub const DEFAULT_MAX_INACTIVE_STREAMS: usize = 64;
+
+/// Stream dependency tree.
+///
+/// > To avoid these problems, an endpoint SHOULD retain stream
+/// > prioritization state for a period after streams become closed.  The
+/// > longer state is retained, the lower the chance that streams are
+/// > assigned incorrect or default priority values.
+/// >
+/// > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-5.3.4)
+///
+/// Nodes of inactive streams (i.e., closed streams and idle streams prioritized by PRIORITY frames)
+/// are retained in the tree until the number of them exceeds the limit.
+#[derive(Debug)]
+pub struct PriorityTree {
+    nodes: HashMap<StreamId, Node>,
+    inactive_streams: VecDeque<StreamId>,
+    max_inactive_streams: usize,
+}
+impl PriorityTree {
+    /// Makes a new `PriorityTree` instance which consists only of the root (stream 0x0).
+    pub fn new() -> Self {
+        Self::with_max_inactive_streams(DEFAULT_MAX_INACTIVE_STREAMS)
+    }
+
+    /// Makes a new `PriorityTree` instance which retains at most `max` inactive streams.
+    pub fn with_max_inactive_streams(max: usize) -> Self {
+        let mut nodes = HashMap::new();
+        nodes.insert(StreamId::connection_control_stream_id(), Node::root());
+        PriorityTree {
+            nodes,
+            inactive_streams: VecDeque::new(),
+            max_inactive_streams: max,
+        }
+    }
+
+    pub fn contains(&self, stream_id: StreamId) -> bool {
+        self.nodes.contains_key(&stream_id)
+    }
+
+    /// Returns the parent of the stream.
+    pub fn parent(&self, stream_id: StreamId) -> Option<StreamId> {
+        self.nodes.get(&stream_id).and_then(|n| n.parent)
+    }
+
+    /// Returns the children of the stream.
+    pub fn children(&self, stream_id: StreamId) -> Option<&[StreamId]> {
+        self.nodes.get(&stream_id).map(|n| &n.children[..])
+    }
+
+    pub fn weight(&self, stream_id: StreamId) -> Option<Weight> {
+        self.nodes.get(&stream_id).map(|n| n.weight)
+    }
+
+    pub fn is_active(&self, stream_id: StreamId) -> bool {
+        if let Some(node) = self.nodes.get(&stream_id) {
+            node.is_active
+        } else {
+            false
+        }
+    }
+
+    /// Marks the stream as active (i.e., open or half-closed).
+    ///
+    /// If the stream is not in the tree, it is added with the default priority.
+    pub fn open(&mut self, stream_id: StreamId) {
+        if !self.contains(stream_id) {
+            self.insert(stream_id, Priority::default());
+        }
+        self.nodes.get_mut(&stream_id).expect("Never fails").is_active = true;
+        self.inactive_streams.retain(|&id| id != stream_id);
+    }
+
+    /// Marks the stream as closed.
+    ///
+    /// The node of the stream is retained for a while for prioritizing other streams.
+    pub fn close(&mut self, stream_id: StreamId) {
+        if let Some(node) = self.nodes.get_mut(&stream_id) {
+            if !node.is_active {
+                return;
+            }
+            node.is_active = false;
+        } else {
+            return;
+        }
+        self.push_inactive_stream(stream_id);
+    }
+
+    /// Sets the priority of the stream.
+    ///
+    /// If the stream is not in the tree, a new (inactive) node is added.
+    ///
+    /// > A stream cannot depend on itself.  An endpoint MUST treat this as a
+    /// > stream error (Section 5.4.2) of type PROTOCOL_ERROR.
+    /// >
+    /// > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-5.3.1)
+    pub fn set_priority(&mut self, stream_id: StreamId, priority: Priority) -> Result<()> {
+        track_assert!(
+            !stream_id.is_connection_control_stream(),
+            ErrorKind::ProtocolError
+        );
+        track_assert_ne!(
+            stream_id,
+            priority.stream_dependency,
+            ErrorKind::ProtocolError
+        );
+        if self.contains(stream_id) {
+            self.reprioritize(stream_id, priority);
+        } else {
+            self.insert(stream_id, priority);
+            self.push_inactive_stream(stream_id);
+        }
+        Ok(())
+    }
+
+    /// Selects the stream to which the next resources should be allocated.
+    ///
+    /// `is_ready` tells whether a stream has something to send.
+    ///
+    /// > Streams can be prioritized by marking them as dependent on the
+    /// > completion of other streams (Section 5.3.1).  Each dependency is
+    /// > assigned a relative weight, a number that is used to determine the
+    /// > relative proportion of available resources that are assigned to
+    /// > streams dependent on the same stream.
+    /// >
+    /// > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-5.3)
+    ///
+    /// A parent stream takes precedence over its dependencies,
+    /// and siblings share the resources in proportion to their weights
+    /// (the amounts are reported via `consume` method).
+    pub fn next_stream<F>(&mut self, is_ready: F) -> Option<StreamId>
+    where
+        F: Fn(StreamId) -> bool,
+    {
+        let root = StreamId::connection_control_stream_id();
+        let path = self.find_ready_path(root, &is_ready)?;
+        for i in 1..path.len() {
+            let parent_vtime = self.nodes[&path[i - 1]].vtime;
+            let pass = cmp::max(self.nodes[&path[i]].pass, parent_vtime);
+            self.set_pass(path[i], pass);
+            self.nodes.get_mut(&path[i - 1]).expect("Never fails").vtime = pass;
+        }
+        path.last().cloned()
+    }
+
+    /// Records that `amount` octets have been sent on the stream.
+    pub fn consume(&mut self, stream_id: StreamId, amount: u32) {
+        let mut current = Some(stream_id);
+        while let Some(id) = current {
+            if id.is_connection_control_stream() {
+                break;
+            }
+            let (pass, parent) = if let Some(node) = self.nodes.get(&id) {
+                let pass = node.pass + u64::from(amount) * 256 / u64::from(node.weight.as_u16());
+                (pass, node.parent)
+            } else {
+                break;
+            };
+            self.set_pass(id, pass);
+            current = parent;
+        }
+    }
+
+    /// Returns the path from `stream_id` to the first ready stream in the depth-first order.
+    ///
+    /// Siblings are visited in the ascending order of their `pass` values.
+    /// The tree is traversed without recursion because it can be arbitrarily deep.
+    fn find_ready_path<F>(&self, stream_id: StreamId, is_ready: &F) -> Option<Vec<StreamId>>
+    where
+        F: Fn(StreamId) -> bool,
+    {
+        let mut path = Vec::new();
+        let mut stack = vec![(stream_id, 0)];
+        while let Some((id, depth)) = stack.pop() {
+            path.truncate(depth);
+            path.push(id);
+            if !id.is_connection_control_stream() && is_ready(id) {
+                return Some(path);
+            }
+            let node = &self.nodes[&id];
+            stack.extend(node.queue.iter().rev().map(|&(_, child)| (child, depth + 1)));
+        }
+        None
+    }
+    fn set_pass(&mut self, stream_id: StreamId, pass: u64) {
+        let (old_pass, parent) = {
+            let node = self.nodes.get_mut(&stream_id).expect("Never fails");
+            (mem::replace(&mut node.pass, pass), node.parent)
+        };
+        if let Some(parent) = parent {
+            let queue = &mut self.nodes.get_mut(&parent).expect("Never fails").queue;
+            queue.remove(&(old_pass, stream_id));
+            queue.insert((pass, stream_id));
+        }
+    }
+    fn insert(&mut self, stream_id: StreamId, priority: Priority) {
+        // > A dependency on a stream that is not currently in the tree -- such
+        // > as a stream in the "idle" state -- results in that stream being given
+        // > a default priority (Section 5.3.5).
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-5.3.1)
+        let priority = if self.contains(priority.stream_dependency) {
+            priority
+        } else {
+            Priority::default()
+        };
+        self.nodes.insert(stream_id, Node::new(priority.weight));
+        self.attach(stream_id, priority.stream_dependency, priority.is_exclusive);
+    }
+    fn reprioritize(&mut self, stream_id: StreamId, priority: Priority) {
+        let priority = if self.contains(priority.stream_dependency) {
+            priority
+        } else {
+            Priority::default()
+        };
+        let parent = priority.stream_dependency;
+
+        // > If a stream is made dependent on one of its own dependencies, the
+        // > formerly dependent stream is first moved to be dependent on the
+        // > reprioritized stream's previous parent.  The moved dependency retains
+        // > its weight.
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-5.3.3)
+        if self.is_descendant(parent, stream_id) {
+            let old_parent = self.parent(stream_id).expect("Never fails");
+            self.detach(parent);
+            self.attach(parent, old_parent, false);
+        }
+        self.detach(stream_id);
+        self.nodes.get_mut(&stream_id).expect("Never fails").weight = priority.weight;
+        self.attach(stream_id, parent, priority.is_exclusive);
+    }
+    fn attach(&mut self, stream_id: StreamId, parent: StreamId, is_exclusive: bool) {
+        // > An exclusive flag allows for the insertion of a new level of
+        // > dependencies.  The exclusive flag causes the stream to become the sole
+        // > dependency of its parent stream, causing other dependencies to become
+        // > dependent on the exclusive stream.
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-5.3.1)
+        let pass = self.nodes[&stream_id].pass;
+        if is_exclusive {
+            let (siblings, sibling_queue) = {
+                let parent = self.nodes.get_mut(&parent).expect("Never fails");
+                let mut queue = BTreeSet::new();
+                queue.insert((pass, stream_id));
+                (
+                    mem::replace(&mut parent.children, vec![stream_id]),
+                    mem::replace(&mut parent.queue, queue),
+                )
+            };
+            for &sibling in &siblings {
+                self.nodes.get_mut(&sibling).expect("Never fails").parent = Some(stream_id);
+            }
+            let node = self.nodes.get_mut(&stream_id).expect("Never fails");
+            node.children.extend(siblings);
+            node.queue.extend(sibling_queue);
+        } else {
+            let parent = self.nodes.get_mut(&parent).expect("Never fails");
+            parent.children.push(stream_id);
+            parent.queue.insert((pass, stream_id));
+        }
+        self.nodes.get_mut(&stream_id).expect("Never fails").parent = Some(parent);
+    }
+    fn detach(&mut self, stream_id: StreamId) {
+        let pass = self.nodes[&stream_id].pass;
+        if let Some(parent) = self.parent(stream_id) {
+            let parent = self.nodes.get_mut(&parent).expect("Never fails");
+            parent.children.retain(|&id| id != stream_id);
+            parent.queue.remove(&(pass, stream_id));
+        }
+        self.nodes.get_mut(&stream_id).expect("Never fails").parent = None;
+    }
+    fn remove(&mut self, stream_id: StreamId) {
+        // > When a stream is removed from the dependency tree, its dependencies
+        // > can be moved to become dependent on the parent of the closed stream.
+        // > The weights of new dependencies are recalculated by distributing the
+        // > weight of the dependency of the closed stream proportionally based on
+        // > the weights of its dependencies.
+        // >
+        // > [RFC 7540](https://tools.ietf.org/html/rfc7540#section-5.3.4)
+        let parent = self.parent(stream_id).expect("Never fails");
+        self.detach(stream_id);
+        let node = self.nodes.remove(&stream_id).expect("Never fails");
+        let total: u32 = node.children
+            .iter()
+            .map(|id| u32::from(self.nodes[id].weight.as_u16()))
+            .sum();
+        for child in node.children {
+            let weight = {
+                let child = &self.nodes[&child];
+                let weight = u32::from(node.weight.as_u16()) * u32::from(child.weight.as_u16()) / total;
+                Weight::new(cmp::max(weight, 1) as u16).expect("Never fails")
+            };
+            let child_node = self.nodes.get_mut(&child).expect("Never fails");
+            child_node.weight = weight;
+            child_node.parent = None;
+            self.attach(child, parent, false);
+        }
+    }
+    fn push_inactive_stream(&mut self, stream_id: StreamId) {
+        self.inactive_streams.push_back(stream_id);
+        while self.inactive_streams.len() > self.max_inactive_streams {
+            let oldest = self.inactive_streams.pop_front().expect("Never fails");
+            self.remove(oldest);
+        }
+    }
+    fn is_descendant(&self, stream_id: StreamId, ancestor: StreamId) -> bool {
+        let mut current = self.parent(stream_id);
+        while let Some(id) = current {
+            if id == ancestor {
+                return true;
+            }
+            current = self.parent(id);
+        }
+        false
+    }
+}
+impl Default for PriorityTree {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug)]
+struct Node {
+    parent: Option<StreamId>,
+    weight: Weight,
+    children: Vec<StreamId>,
+
+    // The children ordered by their `pass` values.
+    queue: BTreeSet<(u64, StreamId)>,
+    is_active: bool,
+
+    // Virtual time used for weighted scheduling among siblings (i.e., stride scheduling).
+    pass: u64,
+
+    // The `pass` value of the most recently scheduled child.
+    vtime: u64,
+}
+impl Node {
+    fn root() -> Self {
+        Node {
+            parent: None,
+            weight: Priority::default().weight,
+            children: Vec::new(),
+            queue: BTreeSet::new(),
+            is_active: true,
+            pass: 0,
+            vtime: 0,
+        }
+    }
+    fn new(weight: Weight) -> Self {
+        Node {
+            parent: None,
+            weight,
+            children: Vec::new(),
+            queue: BTreeSet::new(),
+            is_active: false,
+            pass: 0,
+            vtime: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn priority(dependency: u8, weight: u16, is_exclusive: bool) -> Priority {
+        Priority {
+            is_exclusive,
+            stream_dependency: dependency.into(),
+            weight: track_try_unwrap!(Weight::new(weight)),
+        }
+    }
+
+    #[test]
+    fn exclusive_insertion_works() {
+        // > Figure 4: Example of Exclusive Dependency Creation
+        let mut tree = PriorityTree::new();
+        tree.open(1u8.into());
+        tree.open(3u8.into());
+        track_try_unwrap!(tree.set_priority(5u8.into(), priority(1, 16, true)));
+        track_try_unwrap!(tree.set_priority(7u8.into(), priority(1, 16, false)));
+        track_try_unwrap!(tree.set_priority(9u8.into(), priority(7, 16, true)));
+
+        assert_eq!(tree.children(0u8.into()), Some(&[1u8.into(), 3u8.into()][..]));
+        assert_eq!(tree.children(1u8.into()), Some(&[5u8.into(), 7u8.into()][..]));
+        assert_eq!(tree.children(7u8.into()), Some(&[9u8.into()][..]));
+
+        track_try_unwrap!(tree.set_priority(11u8.into(), priority(1, 16, true)));
+        assert_eq!(tree.children(1u8.into()), Some(&[11u8.into()][..]));
+        assert_eq!(tree.children(11u8.into()), Some(&[5u8.into(), 7u8.into()][..]));
+        assert_eq!(tree.parent(5u8.into()), Some(11u8.into()));
+    }
+
+    #[test]
+    fn reprioritization_works() {
+        // > Figure 5: Example Dependency Tree
+        //
+        //     0
+        //     |
+        //     1
+        //    / \
+        //   3   5
+        //   |
+        //   7
+        let mut tree = PriorityTree::new();
+        track_try_unwrap!(tree.set_priority(1u8.into(), priority(0, 16, false)));
+        track_try_unwrap!(tree.set_priority(3u8.into(), priority(1, 16, false)));
+        track_try_unwrap!(tree.set_priority(5u8.into(), priority(1, 16, false)));
+        track_try_unwrap!(tree.set_priority(7u8.into(), priority(3, 16, false)));
+
+        // > Figure 6: Example of Dependency Reordering (non-exclusive)
+        track_try_unwrap!(tree.set_priority(1u8.into(), priority(3, 32, false)));
+        assert_eq!(tree.parent(3u8.into()), Some(0u8.into()));
+        assert_eq!(tree.parent(1u8.into()), Some(3u8.into()));
+        assert_eq!(tree.children(3u8.into()), Some(&[7u8.into(), 1u8.into()][..]));
+        assert_eq!(tree.children(1u8.into()), Some(&[5u8.into()][..]));
+        assert_eq!(tree.weight(1u8.into()).map(|w| w.as_u16()), Some(32));
+        assert_eq!(tree.weight(3u8.into()).map(|w| w.as_u16()), Some(16));
+
+        // exclusive
+        track_try_unwrap!(tree.set_priority(5u8.into(), priority(3, 16, true)));
+        assert_eq!(tree.children(3u8.into()), Some(&[5u8.into()][..]));
+        assert_eq!(tree.children(5u8.into()), Some(&[7u8.into(), 1u8.into()][..]));
+    }
+
+    #[test]
+    fn self_dependency_is_rejected() {
+        let mut tree = PriorityTree::new();
+        let e = tree.set_priority(1u8.into(), priority(1, 16, false)).err();
+        match e.as_ref().map(|e| e.kind()) {
+            Some(&ErrorKind::ProtocolError) => {}
+            kind => panic!("{:?}", kind),
+        }
+    }
+
+    #[test]
+    fn unknown_dependency_results_in_default_priority() {
+        let mut tree = PriorityTree::new();
+        track_try_unwrap!(tree.set_priority(1u8.into(), priority(3, 100, true)));
+        assert_eq!(tree.parent(1u8.into()), Some(0u8.into()));
+        assert_eq!(tree.weight(1u8.into()).map(|w| w.as_u16()), Some(16));
+    }
+
+    #[test]
+    fn weighted_scheduling_works() {
+        let mut tree = PriorityTree::new();
+        tree.open(1u8.into());
+        tree.open(3u8.into());
+        tree.open(5u8.into());
+        track_try_unwrap!(tree.set_priority(1u8.into(), priority(0, 64, false)));
+        track_try_unwrap!(tree.set_priority(3u8.into(), priority(0, 16, false)));
+        track_try_unwrap!(tree.set_priority(5u8.into(), priority(1, 16, false)));
+
+        // The stream 5 depends on the stream 1 which has nothing to send.
+        let mut counts = HashMap::new();
+        for _ in 0..100 {
+            let id = tree.next_stream(|id| id != 1u8.into()).unwrap();
+            tree.consume(id, 1000);
+            *counts.entry(id.as_u32()).or_insert(0) += 1;
+        }
+        assert_eq!(counts[&5], 80);
+        assert_eq!(counts[&3], 20);
+
+        // A parent takes precedence over its dependencies.
+        let id = tree.next_stream(|_| true);
+        assert_eq!(id, Some(1u8.into()));
+    }
+
+    #[test]
+    fn closed_streams_are_retained() {
+        let mut tree = PriorityTree::with_max_inactive_streams(1);
+        tree.open(1u8.into());
+        track_try_unwrap!(tree.set_priority(3u8.into(), priority(1, 16, false)));
+        tree.open(3u8.into());
+        track_try_unwrap!(tree.set_priority(5u8.into(), priority(1, 16, false)));
+        tree.open(5u8.into());
+
+        tree.close(1u8.into());
+        assert!(tree.contains(1u8.into()));
+        assert!(!tree.is_active(1u8.into()));
+
+        // The stream 1 is evicted and its weight is distributed among its children.
+        tree.close(3u8.into());
+        assert!(!tree.contains(1u8.into()));
+        assert_eq!(tree.children(0u8.into()), Some(&[3u8.into(), 5u8.into()][..]));
+        assert_eq!(tree.weight(3u8.into()).map(|w| w.as_u16()), Some(8));
+        assert_eq!(tree.weight(5u8.into()).map(|w| w.as_u16()), Some(8));
+    }
+
+    #[test]
+    fn deep_trees_are_scheduled_without_recursion() {
+        let mut tree = PriorityTree::with_max_inactive_streams(usize::max_value());
+        let mut parent = 0;
+        for i in 0..20_000 {
+            let id = i * 2 + 1;
+            let priority = Priority {
+                is_exclusive: false,
+                stream_dependency: StreamId::new_unchecked(parent),
+                weight: track_try_unwrap!(Weight::new(16)),
+            };
+            track_try_unwrap!(tree.set_priority(StreamId::new_unchecked(id), priority));
+            parent = id;
+        }
+        let leaf = StreamId::new_unchecked(parent);
+        tree.open(leaf);
+        assert_eq!(tree.next_stream(|id| id == leaf), Some(leaf));
+        assert_eq!(tree.next_stream(|_| false), None);
+    }
+}
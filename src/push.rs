@@ -0,0 +1,38 @@
+// https://tools.ietf.org/html/rfc7540#section-8.2
+use std::fmt;
+
+use header::Header;
+use stream::StreamId;
+
+/// Policy which decides whether streams pushed by a server are accepted.
+///
+/// Refused streams are reset by RST_STREAM frames with the error code `CANCEL`.
+pub trait PushPolicy: fmt::Debug + Send + Sync {
+    /// Returns `true` if the stream promised on the stream `parent` should be accepted.
+    ///
+    /// `promised_request` is the request header carried by the PUSH_PROMISE frame.
+    fn accept(&self, parent: StreamId, promised_request: &Header) -> bool;
+}
+
+/// Accepts every pushed stream.
+///
+/// This is the default policy.
+#[derive(Debug, Default, Clone)]
+pub struct AcceptAllPushes;
+impl PushPolicy for AcceptAllPushes {
+    fn accept(&self, _parent: StreamId, _promised_request: &Header) -> bool {
+        true
+    }
+}
+
+/// Refuses every pushed stream.
+///
+/// Note that setting `SETTINGS_ENABLE_PUSH` to `false` is the preferred way to disable server push
+/// (see `ConnectionBuilder::enable_push`).
+#[derive(Debug, Default, Clone)]
+pub struct RefuseAllPushes;
+impl PushPolicy for RefuseAllPushes {
+    fn accept(&self, _parent: StreamId, _promised_request: &Header) -> bool {
+        false
+    }
+}
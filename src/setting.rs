@@ -9,9 +9,9 @@ const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
 const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
 const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
 
-const MAX_FLOW_CONTROL_WINDOW_SIZE: u32 = (1 << 31) - 1;
+pub(crate) const MAX_FLOW_CONTROL_WINDOW_SIZE: u32 = (1 << 31) - 1;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Settings {
     pub header_table_size: u32,
     pub enable_push: bool,
@@ -20,6 +20,44 @@ pub struct Settings {
     pub max_frame_size: u32,
     pub max_header_list_size: Option<u32>, // `None` means infinite
 }
+impl Settings {
+    /// Updates the parameter specified by `setting`.
+    pub fn apply(&mut self, setting: &Setting) {
+        match *setting {
+            Setting::HeaderTableSize(v) => self.header_table_size = v,
+            Setting::EnablePush(v) => self.enable_push = v,
+            Setting::MaxConcurrentStreams(v) => self.max_concurrent_streams = Some(v),
+            Setting::InitialWindowSize(v) => self.initial_window_size = v,
+            Setting::MaxFrameSize(v) => self.max_frame_size = v,
+            Setting::MaxHeaderListSize(v) => self.max_header_list_size = Some(v),
+        }
+    }
+
+    /// Returns the list of the parameters which differ from the initial values defined in RFC 7540.
+    pub fn to_vec(&self) -> Vec<Setting> {
+        let default = Settings::default();
+        let mut settings = Vec::new();
+        if self.header_table_size != default.header_table_size {
+            settings.push(Setting::HeaderTableSize(self.header_table_size));
+        }
+        if self.enable_push != default.enable_push {
+            settings.push(Setting::EnablePush(self.enable_push));
+        }
+        if let Some(v) = self.max_concurrent_streams {
+            settings.push(Setting::MaxConcurrentStreams(v));
+        }
+        if self.initial_window_size != default.initial_window_size {
+            settings.push(Setting::InitialWindowSize(self.initial_window_size));
+        }
+        if self.max_frame_size != default.max_frame_size {
+            settings.push(Setting::MaxFrameSize(self.max_frame_size));
+        }
+        if let Some(v) = self.max_header_list_size {
+            settings.push(Setting::MaxHeaderListSize(v));
+        }
+        settings
+    }
+}
 impl Default for Settings {
     fn default() -> Self {
         // https://tools.ietf.org/html/rfc7540#section-11.3
@@ -63,7 +101,7 @@ impl Setting {
             }
             SETTINGS_MAX_FRAME_SIZE => {
                 track_assert!(1 << 14 <= value, ErrorKind::ProtocolError);
-                track_assert!(value <= 1 << 24 - 1, ErrorKind::ProtocolError);
+                track_assert!(value < 1 << 24, ErrorKind::ProtocolError);
                 Setting::MaxFrameSize(value)
             }
             SETTINGS_MAX_HEADER_LIST_SIZE => Setting::MaxHeaderListSize(value),
@@ -1,12 +1,17 @@
+use std::cmp;
+use std::collections::VecDeque;
 use std::io::Read;
 use byteorder::{ByteOrder, BigEndian};
-use fibers::sync::mpsc;
-use futures::{Future, Poll};
+use fibers::sync::{mpsc, oneshot};
+use futures::{self, Future, Poll, Async, AsyncSink, StartSend};
 use handy_async::io::AsyncRead;
 use handy_async::io::futures::ReadExact;
+use trackable::error::ErrorKindExt;
 
 use {Result, ErrorKind, Error};
 use connection::Bytes;
+use flow_control::Window;
+use frame::DataFrame;
 use header::Header;
 
 /// Stream Identifier:  A stream identifier (see Section 5.1.1) expressed
@@ -75,59 +80,859 @@ impl<R: Read> Future for ReadStreamId<R> {
     }
 }
 
+/// A stream of a connection.
+///
+/// This is a `futures::Stream` of the items received from the peer.
+/// It ends when the peer closes its side of the stream,
+/// and fails if the stream is reset.
+///
+/// The flow-control windows for received DATA are replenished as the data is taken from this stream.
+///
+/// This is also a `futures::Sink` of the items sent to the peer.
+/// It accepts DATA only while the amount of the queued data is less than
+/// both the send window of the stream and the maximum size of the send queue.
+/// Closing the sink closes our side of the stream.
 #[derive(Debug)]
 pub struct Stream {
     id: StreamId,
-    tx: mpsc::Sender<(StreamId, StreamItem)>,
-    rx: mpsc::Receiver<StreamItem>,
+    tx: mpsc::Sender<(StreamId, StreamCommand)>,
+    rx: mpsc::Receiver<Result<StreamItem>>,
+
+    // The number of octets of DATA which can be queued.
+    send_credit: i64,
+    send_credit_rx: mpsc::Receiver<i64>,
+    is_end_stream_sent: bool,
 }
 impl Stream {
-    pub fn new(id: StreamId, tx: mpsc::Sender<(StreamId, StreamItem)>) -> (Self, StreamHandle) {
+    pub fn new(
+        id: StreamId,
+        tx: mpsc::Sender<(StreamId, StreamCommand)>,
+        send_window_size: u32,
+        recv_window_size: u32,
+        max_send_queue_size: u32,
+    ) -> (Self, StreamHandle) {
         let (handle_tx, rx) = mpsc::channel();
-        let handle = StreamHandle::new(handle_tx);
-        (Stream { id, tx, rx }, handle)
+        let (send_credit_tx, send_credit_rx) = mpsc::channel();
+        let handle = StreamHandle::new(
+            id,
+            handle_tx,
+            send_credit_tx,
+            send_window_size,
+            recv_window_size,
+            max_send_queue_size,
+        );
+        let stream = Stream {
+            id,
+            tx,
+            rx,
+            send_credit: handle.send_credit(),
+            send_credit_rx,
+            is_end_stream_sent: false,
+        };
+        (stream, handle)
+    }
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    /// Sends `header` as a HEADERS frame.
+    ///
+    /// If `end_stream` is `true`, our side of this stream is closed.
+    ///
+    /// See also `Connection::send_headers`.
+    ///
+    /// # Errors
+    ///
+    /// If the connection has been dropped, an `ErrorKind::Cancel` error will be returned.
+    pub fn send_headers(&mut self, header: Header, end_stream: bool) -> Result<()> {
+        let command = StreamCommand::SendHeaders { header, end_stream };
+        track!(self.send_command(command))?;
+        self.is_end_stream_sent |= end_stream;
+        Ok(())
+    }
+
+    /// Sends `data` as DATA frames.
+    ///
+    /// If `end_stream` is `true`, our side of this stream is closed.
+    ///
+    /// Unlike `start_send` of `futures::Sink`, this method does not wait for the send queue.
+    ///
+    /// See also `Connection::send_data`.
+    ///
+    /// # Errors
+    ///
+    /// If the send queue is full (i.e., `start_send` would not be ready),
+    /// an `ErrorKind::InternalError` error will be returned and `data` is not sent.
+    /// The stream is kept open, so the data can be sent again later.
+    ///
+    /// If the connection has been dropped, an `ErrorKind::Cancel` error will be returned.
+    pub fn send_data(&mut self, data: Bytes, end_stream: bool) -> Result<()> {
+        let size = data.as_ref().len();
+        if size > 0 {
+            track_assert!(
+                track!(self.poll_send_credit())?,
+                ErrorKind::InternalError,
+                "Send queue is full: stream_id={:?}",
+                self.id
+            );
+        }
+        let command = StreamCommand::SendData { data, end_stream };
+        track!(self.send_command(command))?;
+        self.send_credit -= size as i64;
+        self.is_end_stream_sent |= end_stream;
+        Ok(())
+    }
+
+    /// Sends `trailers` as a HEADERS frame which closes our side of this stream.
+    ///
+    /// The trailers are sent after all the data passed to `send_data` has been sent.
+    ///
+    /// # Errors
+    ///
+    /// If the connection has been dropped, an `ErrorKind::Cancel` error will be returned.
+    pub fn send_trailers(&mut self, trailers: Header) -> Result<()> {
+        track!(self.send_command(StreamCommand::SendTrailers(trailers)))?;
+        self.is_end_stream_sent = true;
+        Ok(())
+    }
+
+    /// Resets this stream by sending a RST_STREAM frame with the error code of `kind`.
+    ///
+    /// # Errors
+    ///
+    /// If the connection has been dropped, an `ErrorKind::Cancel` error will be returned.
+    pub fn reset(&mut self, kind: ErrorKind) -> Result<()> {
+        track!(self.send_command(StreamCommand::Reset(kind.into())))?;
+        self.is_end_stream_sent = true;
+        Ok(())
+    }
+
+    /// Promises the client to push the response for `request_header` (server only).
+    ///
+    /// The resulting future is resolved with the promised stream on which the response should be sent.
+    ///
+    /// See also `Connection::push_promise`.
+    pub fn push_promise(&self, request_header: Header) -> PushPromise {
+        let (monitored, monitor) = oneshot::monitor();
+        let command = StreamCommand::PushPromise(request_header, monitored);
+
+        // If the connection has been dropped, the resulting future fails
+        // since `monitored` is dropped together with `command`.
+        let _ = self.tx.send((self.id, command));
+        PushPromise(monitor)
+    }
+
+    fn send_command(&self, command: StreamCommand) -> Result<()> {
+        track!(self.tx.send((self.id, command)).map_err(|_| {
+            Error::from(ErrorKind::Cancel.cause("The connection has been dropped"))
+        }))
+    }
+
+    /// Returns `true` if the send queue of this stream can accept more DATA.
+    fn poll_send_credit(&mut self) -> Result<bool> {
+        while let Async::Ready(delta) =
+            futures::Stream::poll(&mut self.send_credit_rx).expect("Never fails")
+        {
+            if let Some(delta) = delta {
+                self.send_credit += delta;
+            } else {
+                // The stream has been closed or reset.
+                track_panic!(ErrorKind::StreamClosed, "stream_id={:?}", self.id);
+            }
+        }
+        Ok(self.send_credit > 0)
+    }
+}
+impl futures::Stream for Stream {
+    type Item = StreamItem;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.rx.poll().expect("Never fails") {
+            Async::Ready(Some(Ok(item))) => {
+                if let StreamItem::Data(ref data) = item {
+                    let size = data.as_ref().len() as u32;
+                    track!(self.send_command(StreamCommand::DataRead(size)))?;
+                }
+                Ok(Async::Ready(Some(item)))
+            }
+            Async::Ready(Some(Err(e))) => Err(track!(e)),
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+impl futures::Sink for Stream {
+    type SinkItem = StreamItem;
+    type SinkError = Error;
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        match item {
+            StreamItem::Header(header) => track!(self.send_headers(header, false))?,
+            StreamItem::Data(data) => {
+                if !track!(self.poll_send_credit())? {
+                    return Ok(AsyncSink::NotReady(StreamItem::Data(data)));
+                }
+                track!(self.send_data(data, false))?;
+            }
+            StreamItem::Trailers(trailers) => track!(self.send_trailers(trailers))?,
+        }
+        Ok(AsyncSink::Ready)
+    }
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        // The items have already been handed to the connection.
+        Ok(Async::Ready(()))
+    }
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        if !self.is_end_stream_sent {
+            track!(self.send_data(Bytes::new(Vec::new()), true))?;
+        }
+        Ok(Async::Ready(()))
+    }
+}
+impl Drop for Stream {
+    fn drop(&mut self) {
+        // The unread data will never be read, so its windows are returned to the peer.
+        let mut size = 0;
+        while let Ok(Async::Ready(Some(item))) = futures::Stream::poll(&mut self.rx) {
+            if let Ok(StreamItem::Data(data)) = item {
+                size += data.as_ref().len() as u32;
+            }
+        }
+        if size > 0 {
+            let _ = self.tx.send((self.id, StreamCommand::DataRead(size)));
+        }
     }
 }
 
+/// A future which will be resolved with a promised stream.
+///
+/// This is created by calling `Stream::push_promise` method.
 #[derive(Debug)]
+pub struct PushPromise(oneshot::Monitor<Stream, Error>);
+impl Future for PushPromise {
+    type Item = Stream;
+    type Error = Error;
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.0.poll() {
+            Err(e) => Err(track!(e.unwrap_or_else(|| {
+                ErrorKind::Cancel.cause("The connection has been dropped").into()
+            }))),
+            Ok(Async::Ready(stream)) => Ok(Async::Ready(stream)),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+        }
+    }
+}
+
+/// https://tools.ietf.org/html/rfc7540#section-5.1
+///
+/// ```text
+///                             +--------+
+///                     send PP |        | recv PP
+///                    ,--------|  idle  |--------.
+///                   /         |        |         \
+///                  v          +--------+          v
+///           +----------+          |           +----------+
+///           |          |          | send H /  |          |
+///    ,------| reserved |          | recv H    | reserved |------.
+///    |      | (local)  |          |           | (remote) |      |
+///    |      +----------+          v           +----------+      |
+///    |          |             +--------+             |          |
+///    |          |     recv ES |        | send ES     |          |
+///    |   send H |     ,-------|  open  |-------.     | recv H   |
+///    |          |    /        |        |        \    |          |
+///    |          v   v         +--------+         v   v          |
+///    |      +----------+          |           +----------+      |
+///    |      |   half   |          |           |   half   |      |
+///    |      |  closed  |          | send R /  |  closed  |      |
+///    |      | (remote) |          | recv R    | (local)  |      |
+///    |      +----------+          |           +----------+      |
+///    |           |                |                 |           |
+///    |           | send ES /      |       recv ES / |           |
+///    |           | send R /       v        send R / |           |
+///    |           | recv R     +--------+   recv R   |           |
+///    | send R /  `----------->|        |<-----------'  send R / |
+///    | recv R                 | closed |               recv R   |
+///    `----------------------->|        |<----------------------'
+///                             +--------+
+///
+///       send:   endpoint sends this frame
+///       recv:   endpoint receives this frame
+///
+///       H:  HEADERS frame (with implied CONTINUATIONs)
+///       PP: PUSH_PROMISE frame (with implied CONTINUATIONs)
+///       ES: END_STREAM flag
+///       R:  RST_STREAM frame
+///
+///                          Figure 2: Stream States
+/// ```
+///
+/// An illegal transition fails with `ErrorKind::StreamClosed` if it should be treated as a
+/// stream error, and with `ErrorKind::ProtocolError` if it should be treated as a connection error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum StreamState {
     Idle,
+    ReservedLocal,
     ReservedRemote,
     Open,
     HalfClosedLocal,
     HalfClosedRemote,
     Closed,
 }
+impl StreamState {
+    pub fn recv_headers(self, end_stream: bool) -> Result<Self> {
+        Ok(match self {
+            StreamState::Idle | StreamState::Open => {
+                if end_stream {
+                    StreamState::HalfClosedRemote
+                } else {
+                    StreamState::Open
+                }
+            }
+            StreamState::ReservedRemote | StreamState::HalfClosedLocal => {
+                if end_stream {
+                    StreamState::Closed
+                } else {
+                    StreamState::HalfClosedLocal
+                }
+            }
+            StreamState::HalfClosedRemote | StreamState::Closed => {
+                track_panic!(ErrorKind::StreamClosed, "state={:?}", self)
+            }
+            StreamState::ReservedLocal => track_panic!(ErrorKind::ProtocolError, "state={:?}", self),
+        })
+    }
+    pub fn send_headers(self, end_stream: bool) -> Result<Self> {
+        Ok(match self {
+            StreamState::Idle | StreamState::Open => {
+                if end_stream {
+                    StreamState::HalfClosedLocal
+                } else {
+                    StreamState::Open
+                }
+            }
+            StreamState::ReservedLocal | StreamState::HalfClosedRemote => {
+                if end_stream {
+                    StreamState::Closed
+                } else {
+                    StreamState::HalfClosedRemote
+                }
+            }
+            StreamState::HalfClosedLocal | StreamState::Closed => {
+                track_panic!(ErrorKind::StreamClosed, "state={:?}", self)
+            }
+            StreamState::ReservedRemote => track_panic!(ErrorKind::ProtocolError, "state={:?}", self),
+        })
+    }
+    pub fn recv_data(self, end_stream: bool) -> Result<Self> {
+        Ok(match self {
+            StreamState::Open => {
+                if end_stream {
+                    StreamState::HalfClosedRemote
+                } else {
+                    StreamState::Open
+                }
+            }
+            StreamState::HalfClosedLocal => {
+                if end_stream {
+                    StreamState::Closed
+                } else {
+                    StreamState::HalfClosedLocal
+                }
+            }
+            StreamState::HalfClosedRemote | StreamState::Closed => {
+                track_panic!(ErrorKind::StreamClosed, "state={:?}", self)
+            }
+            StreamState::Idle | StreamState::ReservedLocal | StreamState::ReservedRemote => {
+                track_panic!(ErrorKind::ProtocolError, "state={:?}", self)
+            }
+        })
+    }
+    pub fn send_data(self, end_stream: bool) -> Result<Self> {
+        Ok(match self {
+            StreamState::Open => {
+                if end_stream {
+                    StreamState::HalfClosedLocal
+                } else {
+                    StreamState::Open
+                }
+            }
+            StreamState::HalfClosedRemote => {
+                if end_stream {
+                    StreamState::Closed
+                } else {
+                    StreamState::HalfClosedRemote
+                }
+            }
+            StreamState::HalfClosedLocal | StreamState::Closed => {
+                track_panic!(ErrorKind::StreamClosed, "state={:?}", self)
+            }
+            StreamState::Idle | StreamState::ReservedLocal | StreamState::ReservedRemote => {
+                track_panic!(ErrorKind::ProtocolError, "state={:?}", self)
+            }
+        })
+    }
+
+    /// Transition of the stream reserved by a received PUSH_PROMISE frame.
+    pub fn recv_push_promise(self) -> Result<Self> {
+        track_assert_eq!(self, StreamState::Idle, ErrorKind::ProtocolError);
+        Ok(StreamState::ReservedRemote)
+    }
+
+    /// Transition of the stream reserved by a sent PUSH_PROMISE frame.
+    pub fn send_push_promise(self) -> Result<Self> {
+        track_assert_eq!(self, StreamState::Idle, ErrorKind::ProtocolError);
+        Ok(StreamState::ReservedLocal)
+    }
+
+    /// Transition caused by a sent or received RST_STREAM frame.
+    pub fn rst_stream(self) -> Result<Self> {
+        track_assert_ne!(self, StreamState::Idle, ErrorKind::ProtocolError);
+        Ok(StreamState::Closed)
+    }
+
+    pub fn is_local_closed(self) -> bool {
+        self == StreamState::HalfClosedLocal || self == StreamState::Closed
+    }
+    pub fn is_remote_closed(self) -> bool {
+        self == StreamState::HalfClosedRemote || self == StreamState::Closed
+    }
+}
 
 #[derive(Debug)]
 pub struct StreamHandle {
-    tx: mpsc::Sender<StreamItem>,
+    id: StreamId,
+
+    // `None` if no more items will be delivered to the `Stream`.
+    tx: Option<mpsc::Sender<Result<StreamItem>>>,
     state: StreamState,
+    send_window: Window,
+    recv_window: Window,
+
+    // Octets of DATA delivered to the `Stream` but not read by the application yet.
+    recv_unread: u32,
+
+    // `None` if the `Stream` can no longer send DATA.
+    send_credit_tx: Option<mpsc::Sender<i64>>,
+
+    // The send credit of which the `Stream` has been informed.
+    reported_send_credit: i64,
+    send_queue: VecDeque<Bytes>,
+    send_queue_offset: usize,
+    send_queue_size: u32,
+    max_send_queue_size: u32,
+    is_end_stream_queued: bool,
+    queued_trailers: Option<Header>,
+    held_header: Option<(Header, bool)>,
+    is_header_received: bool,
 }
 impl StreamHandle {
-    fn new(tx: mpsc::Sender<StreamItem>) -> Self {
-        StreamHandle {
-            tx,
+    fn new(
+        id: StreamId,
+        tx: mpsc::Sender<Result<StreamItem>>,
+        send_credit_tx: mpsc::Sender<i64>,
+        send_window_size: u32,
+        recv_window_size: u32,
+        max_send_queue_size: u32,
+    ) -> Self {
+        let mut handle = StreamHandle {
+            id,
+            tx: Some(tx),
             state: StreamState::Idle,
+            send_window: Window::new(send_window_size),
+            recv_window: Window::new(recv_window_size),
+            recv_unread: 0,
+            send_credit_tx: Some(send_credit_tx),
+            reported_send_credit: 0,
+            send_queue: VecDeque::new(),
+            send_queue_offset: 0,
+            send_queue_size: 0,
+            max_send_queue_size,
+            is_end_stream_queued: false,
+            queued_trailers: None,
+            held_header: None,
+            is_header_received: false,
+        };
+        handle.reported_send_credit = handle.send_credit();
+        handle
+    }
+    pub fn send_window(&self) -> &Window {
+        &self.send_window
+    }
+
+    /// Increases the send window by `increment` (i.e., handles a WINDOW_UPDATE frame).
+    pub fn increase_send_window(&mut self, increment: u32) -> Result<()> {
+        track!(self.send_window.increase(increment))?;
+        self.report_send_credit();
+        Ok(())
+    }
+
+    /// Adjusts the send window to the change of `SETTINGS_INITIAL_WINDOW_SIZE`.
+    pub fn update_initial_send_window_size(&mut self, old: u32, new: u32) -> Result<()> {
+        track!(self.send_window.update_initial_window_size(old, new))?;
+        self.report_send_credit();
+        Ok(())
+    }
+    pub fn recv_window(&self) -> &Window {
+        &self.recv_window
+    }
+    pub fn recv_window_mut(&mut self) -> &mut Window {
+        &mut self.recv_window
+    }
+
+    /// Returns the number of octets of DATA which have been delivered to the `Stream`
+    /// but not read by the application yet.
+    pub fn recv_unread(&self) -> u32 {
+        self.recv_unread
+    }
+
+    /// Marks `size` octets of the delivered DATA as read by the application.
+    pub fn mark_data_read(&mut self, size: u32) {
+        self.recv_unread = self.recv_unread.saturating_sub(size);
+    }
+
+    /// Returns the number of octets of DATA which can be queued
+    /// without exceeding the send window or the maximum size of the send queue.
+    pub fn send_credit(&self) -> i64 {
+        let limit = cmp::min(self.send_window.available(), self.max_send_queue_size);
+        i64::from(limit) - i64::from(self.send_queue_size)
+    }
+
+    /// Marks `size` octets of the send credit as taken by the `Stream`.
+    ///
+    /// This is called before the data sent by the `Stream` is enqueued,
+    /// since the `Stream` has already deducted it from its credit.
+    pub fn take_send_credit(&mut self, size: u32) {
+        self.reported_send_credit -= i64::from(size);
+    }
+
+    /// Enqueues outgoing data which will be sent when the flow-control windows allow.
+    ///
+    /// If the send queue already holds the maximum number of octets,
+    /// an `ErrorKind::InternalError` error will be returned.
+    pub fn enqueue_data(&mut self, data: Bytes, end_stream: bool) -> Result<()> {
+        track!(self.check_end_stream_not_queued())?;
+        if !data.as_ref().is_empty() {
+            track_assert!(
+                self.send_queue_size < self.max_send_queue_size,
+                ErrorKind::InternalError,
+                "Send queue is full: stream_id={:?}, max={}",
+                self.id,
+                self.max_send_queue_size
+            );
+            self.send_queue_size = self.send_queue_size.saturating_add(data.as_ref().len() as u32);
+            self.send_queue.push_back(data);
+        }
+        self.is_end_stream_queued = end_stream;
+        self.report_send_credit();
+        Ok(())
+    }
+
+    /// Enqueues outgoing trailers which will be sent after all the queued data.
+    pub fn enqueue_trailers(&mut self, trailers: Header) -> Result<()> {
+        track!(self.check_end_stream_not_queued())?;
+        self.queued_trailers = Some(trailers);
+        Ok(())
+    }
+
+    /// Returns `true` if `pop_data_frame(max_len)` or `pop_trailers()` will return a value.
+    pub fn has_sendable_data(&self, max_len: u32) -> bool {
+        if self.state != StreamState::Open && self.state != StreamState::HalfClosedRemote {
+            false
+        } else if self.send_queue.is_empty() {
+            self.is_end_stream_queued || self.queued_trailers.is_some()
+        } else {
+            max_len > 0 && self.send_window.available() > 0
+        }
+    }
+
+    /// Takes a DATA frame from the head of the send queue.
+    ///
+    /// The payload of the resulting frame is at most `max_len` octets
+    /// and is consumed from the send window of this stream.
+    pub fn pop_data_frame(&mut self, max_len: u32) -> Option<DataFrame<Bytes>> {
+        let max_len = cmp::min(max_len, self.send_window.available()) as usize;
+        let data = if let Some(data) = self.send_queue.pop_front() {
+            let remaining = data.as_ref().len() - self.send_queue_offset;
+            if max_len == 0 {
+                self.send_queue.push_front(data);
+                return None;
+            } else if remaining <= max_len && self.send_queue_offset == 0 {
+                data
+            } else {
+                let end = self.send_queue_offset + cmp::min(remaining, max_len);
+                let chunk = data.as_ref()[self.send_queue_offset..end].to_vec();
+                if end < data.as_ref().len() {
+                    self.send_queue_offset = end;
+                    self.send_queue.push_front(data);
+                } else {
+                    self.send_queue_offset = 0;
+                }
+                Bytes::new(chunk)
+            }
+        } else if self.is_end_stream_queued {
+            Bytes::new(Vec::new())
+        } else {
+            return None;
+        };
+
+        let len = data.as_ref().len() as u32;
+        self.send_window.consume(len).expect("Never fails");
+        self.send_queue_size = self.send_queue_size.saturating_sub(len);
+        self.report_send_credit();
+
+        let end_stream = self.is_end_stream_queued && self.send_queue.is_empty();
+        if end_stream {
+            self.is_end_stream_queued = false;
         }
+        self.state = self.state.send_data(end_stream).expect("Never fails");
+        Some(DataFrame {
+            stream_id: self.id,
+            end_stream,
+            padding_len: None,
+            data,
+        })
+    }
+
+    /// Takes the queued trailers if all the queued data has been sent.
+    ///
+    /// The trailers should be sent as a HEADERS frame with the END_STREAM flag.
+    pub fn pop_trailers(&mut self) -> Option<Header> {
+        if self.send_queue.is_empty() {
+            self.queued_trailers.take()
+        } else {
+            None
+        }
+    }
+    pub fn state(&self) -> StreamState {
+        self.state
     }
-    pub fn handle_header(&mut self, header: Header) -> Result<()> {
-        // TODO: check state
-        let _ = self.tx.send(StreamItem::Header(header));
+    pub fn is_remote_closed(&self) -> bool {
+        self.state.is_remote_closed()
+    }
+    pub fn is_closed(&self) -> bool {
+        self.state == StreamState::Closed
+    }
+    /// Returns `true` if the (final) header of a request or a response has been received.
+    ///
+    /// Any subsequent header is a trailer.
+    pub fn is_header_received(&self) -> bool {
+        self.is_header_received
+    }
+    pub fn handle_header(&mut self, header: Header, end_stream: bool) -> Result<()> {
+        self.state = track!(self.state.recv_headers(end_stream))?;
+        let item = if self.is_header_received {
+            StreamItem::Trailers(header)
+        } else {
+            if !header.is_informational() {
+                self.is_header_received = true;
+            }
+            StreamItem::Header(header)
+        };
+        self.deliver(Ok(item));
         Ok(())
     }
-    pub fn handle_data(&mut self, data: Vec<u8>) {
-        // TODO: check state
-        let _ = self.tx.send(StreamItem::Data(Bytes::new(data)));
+    pub fn handle_data(&mut self, data: Vec<u8>, end_stream: bool) -> Result<()> {
+        self.state = track!(self.state.recv_data(end_stream))?;
+        if !data.is_empty() {
+            let size = data.len() as u32;
+            if self.deliver(Ok(StreamItem::Data(Bytes::new(data)))) {
+                self.recv_unread += size;
+            }
+        }
+        Ok(())
     }
-    pub fn handle_end_stream(&mut self) {
-        // TODO: check state
-        self.state = StreamState::HalfClosedRemote;
+    pub fn send_headers(&mut self, end_stream: bool) -> Result<()> {
+        self.state = track!(self.state.send_headers(end_stream))?;
+        Ok(())
+    }
+
+    /// Holds `header` until the stream is allowed to send it.
+    pub fn hold_header(&mut self, header: Header, end_stream: bool) -> Result<()> {
+        track_assert!(
+            self.held_header.is_none(),
+            ErrorKind::InternalError,
+            "stream_id={:?}",
+            self.id
+        );
+        self.held_header = Some((header, end_stream));
+        Ok(())
+    }
+    pub fn take_held_header(&mut self) -> Option<(Header, bool)> {
+        self.held_header.take()
+    }
+    pub fn reserve_local(&mut self) -> Result<()> {
+        self.state = track!(self.state.send_push_promise())?;
+        Ok(())
+    }
+    pub fn reserve_remote(&mut self) -> Result<()> {
+        self.state = track!(self.state.recv_push_promise())?;
+        Ok(())
+    }
+
+    /// Closes this stream for a RST_STREAM frame to be sent.
+    pub fn reset(&mut self) -> Result<()> {
+        self.state = track!(self.state.rst_stream())?;
+        self.clear_send_queue();
+        Ok(())
+    }
+
+    /// Closes this stream for `error` (e.g., a received RST_STREAM frame) and
+    /// delivers it to the `Stream`.
+    pub fn handle_error(&mut self, error: Error) -> Result<()> {
+        self.state = track!(self.state.rst_stream())?;
+        self.clear_send_queue();
+        self.deliver(Err(error));
+        Ok(())
+    }
+
+    /// Closes this stream without sending any frame and delivers `error` to the `Stream`.
+    ///
+    /// This is used for the streams in "idle" state, which the peer does not know yet.
+    pub fn discard(&mut self, error: Error) {
+        self.state = StreamState::Closed;
+        self.clear_send_queue();
+        self.held_header = None;
+        self.deliver(Err(error));
+    }
+
+    fn clear_send_queue(&mut self) {
+        self.send_credit_tx = None;
+        self.send_queue.clear();
+        self.send_queue_offset = 0;
+        self.send_queue_size = 0;
+        self.is_end_stream_queued = false;
+        self.queued_trailers = None;
+    }
+
+    /// Informs the `Stream` of the change of the send credit.
+    fn report_send_credit(&mut self) {
+        let credit = self.send_credit();
+        if credit != self.reported_send_credit {
+            if let Some(ref tx) = self.send_credit_tx {
+                let _ = tx.send(credit - self.reported_send_credit);
+            }
+            self.reported_send_credit = credit;
+        }
+    }
+    fn check_end_stream_not_queued(&self) -> Result<()> {
+        track_assert!(
+            !self.state.is_local_closed() && !self.is_end_stream_queued &&
+                self.queued_trailers.is_none(),
+            ErrorKind::StreamClosed,
+            "stream_id={:?}",
+            self.id
+        );
+        Ok(())
+    }
+
+    /// Delivers `item` to the `Stream`.
+    ///
+    /// If the peer has closed its side of the stream, the `Stream` is ended after `item`.
+    ///
+    /// Returns `false` if `item` has been discarded because the `Stream` has been dropped.
+    fn deliver(&mut self, item: Result<StreamItem>) -> bool {
+        let is_delivered = if let Some(ref tx) = self.tx {
+            tx.send(item).is_ok()
+        } else {
+            false
+        };
+        if self.state.is_remote_closed() {
+            self.tx = None;
+        }
+        is_delivered
     }
 }
 
+/// An item received from the peer on a stream.
 #[derive(Debug)]
 pub enum StreamItem {
+    /// The header of a request or a response (including informational responses).
     Header(Header),
     Data(Bytes),
+
+    /// The trailers which follow the data.
+    ///
+    /// This is the last item of the stream.
+    Trailers(Header),
+}
+
+/// A request from a `Stream` to the connection which owns it.
+#[derive(Debug)]
+pub enum StreamCommand {
+    SendHeaders { header: Header, end_stream: bool },
+    SendData { data: Bytes, end_stream: bool },
+    SendTrailers(Header),
+    Reset(Error),
+    PushPromise(Header, oneshot::Monitored<Stream, Error>),
+
+    /// The application has read the given number of octets of DATA.
+    DataRead(u32),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stream_state_works() {
+        // request/response without bodies
+        let state = StreamState::Idle;
+        let state = track_try_unwrap!(state.recv_headers(true));
+        assert_eq!(state, StreamState::HalfClosedRemote);
+        let state = track_try_unwrap!(state.send_headers(true));
+        assert_eq!(state, StreamState::Closed);
+
+        // request with body and trailers
+        let state = StreamState::Idle;
+        let state = track_try_unwrap!(state.send_headers(false));
+        let state = track_try_unwrap!(state.send_data(false));
+        let state = track_try_unwrap!(state.send_headers(true));
+        assert_eq!(state, StreamState::HalfClosedLocal);
+        assert!(state.send_data(false).is_err());
+        let state = track_try_unwrap!(state.recv_headers(false));
+        let state = track_try_unwrap!(state.recv_data(true));
+        assert_eq!(state, StreamState::Closed);
+
+        // server push
+        let state = track_try_unwrap!(StreamState::Idle.send_push_promise());
+        assert_eq!(state, StreamState::ReservedLocal);
+        assert!(state.recv_headers(false).is_err());
+        let state = track_try_unwrap!(state.send_headers(false));
+        assert_eq!(state, StreamState::HalfClosedRemote);
+
+        // illegal frames
+        assert!(StreamState::Idle.recv_data(false).is_err());
+        assert!(StreamState::Idle.rst_stream().is_err());
+        assert!(StreamState::Open.recv_push_promise().is_err());
+        assert!(StreamState::Closed.recv_headers(false).is_err());
+    }
+
+    #[test]
+    fn stream_items_work() {
+        use futures::Stream as FuturesStream;
+
+        let (tx, _rx) = mpsc::channel();
+        let (stream, mut handle) = Stream::new(StreamId::from(1u8), tx, 65_535, 65_535, 65_535);
+        let mut header = Header::new();
+        header.add_field(b":status", b"200");
+        track_try_unwrap!(handle.handle_header(header, false));
+        track_try_unwrap!(handle.handle_data(b"foo".to_vec(), false));
+        let mut trailers = Header::new();
+        trailers.add_field(b"grpc-status", b"0");
+        track_try_unwrap!(handle.handle_header(trailers, true));
+
+        let mut items = FuturesStream::wait(stream);
+        match items.next() {
+            Some(Ok(StreamItem::Header(h))) => assert_eq!(h.get(b":status"), Some(&b"200"[..])),
+            item => panic!("{:?}", item),
+        }
+        match items.next() {
+            Some(Ok(StreamItem::Data(d))) => assert_eq!(d.as_ref(), b"foo"),
+            item => panic!("{:?}", item),
+        }
+        match items.next() {
+            Some(Ok(StreamItem::Trailers(t))) => assert_eq!(t.get(b"grpc-status"), Some(&b"0"[..])),
+            item => panic!("{:?}", item),
+        }
+        assert!(items.next().is_none());
+    }
 }